@@ -1,8 +1,12 @@
-use birdwatcher_rs::{rpc::common::InsightClient, service::Bundle, tui};
+use birdwatcher_rs::{
+    rpc::common::InsightClient,
+    service::{Bundle, OverrideMode},
+    tui,
+};
 use clap::{Parser, Subcommand};
-use color_eyre::eyre::Context;
+use color_eyre::eyre::{eyre, Context};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tarpc::tokio_serde::formats::Bincode;
 use tarpc::{client, context};
 use tokio::net::UnixStream;
@@ -20,27 +24,95 @@ enum Commands {
     Json {},
     /// Show a live view of the services state
     Tui {},
+    /// Make the daemon reload its configuration file
+    Reload {},
+    /// Force the BIRD function of a service to return false, whatever its check says
+    Drain {
+        function_name: String,
+        /// Undrain automatically after this duration. Never by default
+        #[arg(long)]
+        duration_s: Option<u64>,
+        /// Shown in the TUI and the logs
+        #[arg(long, default_value = "drained by birdwatcher-cli")]
+        reason: String,
+    },
+    /// Remove the override of a service: its BIRD function follows its check again
+    Undrain { function_name: String },
+}
+
+const SOCKET_PATH: &str = "/tmp/birdwatcher.sock";
+
+async fn connect() -> color_eyre::Result<InsightClient> {
+    let conn = UnixStream::connect(SOCKET_PATH)
+        .await
+        .wrap_err(format!("While opening {SOCKET_PATH}"))?;
+
+    let codec_builder = tarpc::tokio_util::codec::LengthDelimitedCodec::builder();
+    let transport = tarpc::serde_transport::new(codec_builder.new_framed(conn), Bincode::default());
+    Ok(InsightClient::new(client::Config::default(), transport).spawn())
 }
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> color_eyre::Result<()> {
     let args = CliArg::parse();
-    if let Commands::Json {} = args.command {
-        const SOCKET_PATH: &str = "/tmp/birdwatcher.sock";
-        let conn = UnixStream::connect(SOCKET_PATH)
-            .await
-            .wrap_err(format!("While opening {SOCKET_PATH}"))?;
+    match args.command {
+        Commands::Json {} => {
+            let client = connect().await?;
+
+            let res = client.get_data(context::current()).await?;
 
-        let codec_builder = tarpc::tokio_util::codec::LengthDelimitedCodec::builder();
-        let transport =
-            tarpc::serde_transport::new(codec_builder.new_framed(conn), Bincode::default());
-        let client = InsightClient::new(client::Config::default(), transport).spawn();
+            dbg!(res);
 
-        let res = client.get_data(context::current()).await?;
+            return Ok(());
+        }
+        Commands::Reload {} => {
+            let client = connect().await?;
+
+            // The daemon answers once BIRD has been reloaded, which may be longer than the default deadline
+            let mut ctx = context::current();
+            ctx.deadline = Instant::now() + Duration::from_mins(1);
+            client
+                .reload_config(ctx)
+                .await?
+                .map_err(|e| eyre!("The daemon refused the configuration: {e}"))?;
 
-        dbg!(res);
+            println!("Configuration reloaded");
 
-        return Ok(());
+            return Ok(());
+        }
+        Commands::Drain {
+            function_name,
+            duration_s,
+            reason,
+        } => {
+            let client = connect().await?;
+            client
+                .set_override(
+                    context::current(),
+                    function_name,
+                    OverrideMode::ForcedDown,
+                    duration_s.map(Duration::from_secs),
+                    reason,
+                )
+                .await?
+                .map_err(|e| eyre!(e))?;
+            return Ok(());
+        }
+        Commands::Undrain { function_name } => {
+            let client = connect().await?;
+            client
+                .set_override(
+                    context::current(),
+                    function_name,
+                    OverrideMode::Auto,
+                    None,
+                    String::new(),
+                )
+                .await?
+                .map_err(|e| eyre!(e))?;
+            return Ok(());
+        }
+        Commands::Tui {} => {}
     }
 
     let bundle = Arc::new(Mutex::<Option<Bundle>>::new(None));
@@ -55,7 +127,7 @@ async fn main() -> color_eyre::Result<()> {
         let mut interval = tokio::time::interval(Duration::from_secs(1));
 
         'connection: loop {
-            match UnixStream::connect("/tmp/birdwatcher.sock").await {
+            match UnixStream::connect(SOCKET_PATH).await {
                 Err(_) => {
                     interval.tick().await;
                 }
@@ -1,17 +1,33 @@
 #![feature(never_type)]
 
 use std::{
-    io::Write,
+    collections::HashMap,
     path::{Path, PathBuf},
     sync::Arc,
+    time::{Duration, Instant, SystemTime},
 };
 
 use fs_err::PathExt;
+use inotify::{EventStream, Inotify, WatchMask};
 use opentelemetry::KeyValue;
-use tokio::{net::UnixListener, process::Command, task::JoinSet, time::timeout};
+use tokio::{
+    net::UnixListener,
+    process::Command,
+    signal::unix::{signal, SignalKind},
+    task::{AbortHandle, JoinSet},
+    time::timeout,
+};
 
 use birdwatcher_rs::{
-    config::Config, rpc::common::Insight, rpc::server::InsightServer, service::ServiceState,
+    bird::{install_candidate, render_functions, write_candidate},
+    bird_control,
+    check::{Check, CheckOutcome, CheckStatus},
+    config::{BirdReload, Config, DrainFile, Output, Reload, ShutdownAction},
+    rpc::common::Insight,
+    rpc::server::{DaemonRequest, InsightServer},
+    service::{Bundle, FunctionValue, Override, ReloadStatus, ServiceDefinition, ServiceState},
+    state_file::SavedStates,
+    verify::{exported_prefixes, Verification},
 };
 
 use clap::Parser;
@@ -38,10 +54,18 @@ struct Cli {
     config: PathBuf,
 }
 
+const PID_PATH: &str = "/tmp/birdwatcher.pid";
+/// Where `birdwatcher-cli` connects to
+const SOCKET_PATH: &str = "/tmp/birdwatcher.sock";
+
 /// A message send by a Service task to the main task
 struct ServiceCommandResult {
-    service_id: usize,
-    success: bool,
+    /// The definition the task has been started with.
+    /// After a config reload, the results of a removed or changed service are ignored
+    service_def: Arc<ServiceDefinition>,
+    outcome: CheckOutcome,
+    /// How long the check took
+    duration: Duration,
 }
 
 // opentelemetry metric provider need multi_thread runtime
@@ -56,229 +80,935 @@ async fn main() -> Result<()> {
 
     birdwatcher_rs::telemetry::init_telemetry()?;
 
-    // Contains the only mutable state: a counter for each service
-    let service_states: Vec<ServiceState> = config
-        .service_definitions
+    // Contains the only mutable state: a counter for each service, and what is shown by `birdwatcher-cli`
+    let started_at = SystemTime::now();
+    let mut bundle = Bundle::new(config.clone(), started_at);
+    if let Some(state_file) = &config.state_file {
+        if let Err(e) = SavedStates::restore(
+            Path::new(&state_file.path),
+            state_file.max_age,
+            &mut bundle,
+            started_at,
+        ) {
+            warn!("Cannot restore the state of the services: {e:#}");
+        }
+    }
+    // Watch before checking the drain file, so that its creation cannot be missed
+    let drain_events = config
+        .drain_file
+        .as_ref()
+        .map(watch_drain_file)
+        .transpose()?;
+    bundle.set_drained(drain_file_exists(&config));
+    let bundle = Arc::new(std::sync::Mutex::new(bundle));
+    let meter = opentelemetry::global::meter("birdwatcher");
+    let bird_updater = Arc::new(BirdUpdater::new(&meter));
+
+    let (request_tx, request_rx) = tokio::sync::mpsc::channel(1);
+    setup_birdwatcher_cli_server(bundle.clone(), request_tx).unwrap();
+
+    // Read before being overwritten, for `ShutdownAction::Restore`
+    let original_generated_files: HashMap<String, String> = config
+        .outputs
         .iter()
-        .map(|def|
-            // Start with all services disabled, but only one success is enough to switch to `Success`
-            ServiceState::Failure {
-                nb_of_success: def.rise - 1,
+        .filter_map(|output| {
+            let path = &output.generated_file.path;
+            Some((path.clone(), fs_err::read_to_string(path).ok()?))
         })
         .collect();
-    let service_states: Arc<std::sync::Mutex<Vec<ServiceState>>> =
-        Arc::new(std::sync::Mutex::new(service_states));
-    let config = Arc::new(config);
+    bird_updater.update(&bundle).await;
 
-    setup_birdwatcher_cli_server(service_states.clone(), config.clone()).unwrap();
-
-    write_bird_function(&config, &service_states.lock().unwrap());
-    launch_reload_function(&config).await;
-
-    let meter = opentelemetry::global::meter("birdwatcher");
-    let service_up_instrument = meter
-        .u64_gauge("birdwatcher_service_up")
-        .with_description("0 = The service is down. 1 = The service is up")
-        .build();
-    let service_hysteresis_state_instrument = meter
-        .f64_gauge("birdwatcher_service_hysteresis_state")
-        .with_description("Like service_up, but more detailed. It aggregates the result the last function_return value.
-        It can take intermediate values between 0 and 1 for a failed service raising, or a successful service failing")
-        .build();
     let function_return_value_instrument = meter
         .u64_gauge("birdwatcher_function_return_value")
-        .with_description("Return value of a function.")
+        .with_description("Return value of a function. 0 = failure, 1 = success, 2 = warning")
+        .build();
+    let sub_check_return_value_instrument = meter
+        .u64_gauge("birdwatcher_sub_check_return_value")
+        .with_description(
+            "Result of a sub-check of a composite service. 0 = failure, 1 = success, 2 = warning",
+        )
         .build();
 
     let (tx, rx) = tokio::sync::mpsc::channel(1);
+    let service_tasks = ServiceTasks {
+        tx,
+        function_return_value: function_return_value_instrument,
+        sub_check_return_value: sub_check_return_value_instrument,
+    };
 
     let mut join_set = JoinSet::new();
 
     // Start a task for each service. Each Service task will send update to the main task via `ServiceCommandResult` send by `tx`
-    start_service_tasks(
-        &mut join_set,
-        &config,
-        &tx.clone(),
-        &function_return_value_instrument,
-    );
+    let abort_handles: HashMap<String, AbortHandle> = config
+        .service_definitions
+        .iter()
+        .map(|def| {
+            (
+                def.function_name.clone(),
+                service_tasks.spawn(&mut join_set, def),
+            )
+        })
+        .collect();
 
     info!("All services launched");
 
+    let drain_watcher = drain_events.map(|events| {
+        spawn_drain_watcher(&mut join_set, bundle.clone(), bird_updater.clone(), events)
+    });
+
+    let (update_tx, update_rx) = tokio::sync::mpsc::channel(1);
+    spawn_bird_update_task(
+        &mut join_set,
+        bundle.clone(),
+        bird_updater.clone(),
+        update_rx,
+    );
+
     // Main task. Listen for stream of `ServiceCommandResult` from all the tasks spawned above and update the `service_states` accordingly.
-    // If a service goes up or down, it also updates the generated bird function and launch the reload command.
+    // If a service goes up or down, it also requests an update of the generated bird function and a reload.
     start_main_task(
         &mut join_set,
-        config.clone(),
-        service_states.clone(),
+        bundle.clone(),
+        update_tx.clone(),
+        started_at,
         rx,
-        service_up_instrument,
-        service_hysteresis_state_instrument,
+        MainTaskMetrics::new(&meter),
     );
 
-    // No tasks should terminate (neither a service task or the main task).
-    // If one does exit, this is an error
-    let terminated_task: Result<!, tokio::task::JoinError> = join_set
-        .join_next()
-        .await
-        .ok_or(eyre!("No tasks in the JoinSet ??"))?;
-    let err = terminated_task.unwrap_err();
-    Err(eyre!("A task failed: {}", err))
+    wait_for_requests(
+        &cli.config,
+        &bundle,
+        &bird_updater,
+        &update_tx,
+        Services {
+            tasks: service_tasks,
+            join_set,
+            abort_handles,
+            drain_watcher,
+        },
+        request_rx,
+        original_generated_files,
+    )
+    .await
 }
 
-fn write_bird_function(config: &Config, services_states: &[ServiceState]) {
-    use itertools::Itertools;
-    // Combines the services static definition and their mutable state
-    let services = config.service_definitions.iter().zip(services_states);
-    let content = services
-        .map(|(service_def, service_state)| {
-            let function_name = &service_def.function_name;
-            let return_value = match service_state {
-                ServiceState::Failure { .. } => "false",
-                ServiceState::Success { .. } => "true",
-            };
-            let return_type = if config.generated_file.function_return_type {
-                "-> bool"
-            } else {
-                ""
-            };
-            format!(
-                "
-function {function_name}() {return_type}
-{{
-    return {return_value};
-}}
-",
+/// Reload the configuration on SIGHUP, handle the requests of `birdwatcher-cli` and remove the expired overrides.
+/// Only returns on SIGTERM or SIGINT, once shut down, or if a task terminated
+async fn wait_for_requests(
+    config_path: &Path,
+    bundle: &Arc<std::sync::Mutex<Bundle>>,
+    bird_updater: &Arc<BirdUpdater>,
+    update_tx: &tokio::sync::mpsc::Sender<()>,
+    mut services: Services,
+    mut request_rx: tokio::sync::mpsc::Receiver<DaemonRequest>,
+    original_generated_files: HashMap<String, String>,
+) -> Result<()> {
+    let mut sighup = signal(SignalKind::hangup())?;
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    loop {
+        let next_override_expiry = bundle.lock().unwrap().next_override_expiry();
+        tokio::select! {
+            terminated_task = services.join_set.join_next() => {
+                // No tasks should terminate (neither a service task or the main task), except those aborted by a config reload.
+                // If one does exit, this is an error
+                let terminated_task: Result<!, tokio::task::JoinError> =
+                    terminated_task.ok_or(eyre!("No tasks in the JoinSet ??"))?;
+                let err = terminated_task.unwrap_err();
+                if !err.is_cancelled() {
+                    return Err(eyre!("A task failed: {}", err));
+                }
+            }
+            _ = sighup.recv() => {
+                info!("SIGHUP received, reloading the configuration");
+                if let Err(e) = reload_config(config_path, bundle, bird_updater, update_tx, &mut services) {
+                    error!("New configuration refused, keeping the current one: {e:#}");
+                }
+            }
+            Some(request) = request_rx.recv() => match request {
+                DaemonRequest::ReloadConfig(result) => {
+                    info!("Configuration reload requested by birdwatcher-cli");
+                    let reload_result = reload_config(config_path, bundle, bird_updater, update_tx, &mut services);
+                    if let Err(e) = &reload_result {
+                        error!("New configuration refused, keeping the current one: {e:#}");
+                    }
+                    // birdwatcher-cli may have given up waiting
+                    let _ = result.send(reload_result.map_err(|e| format!("{e:#}")));
+                }
+                DaemonRequest::SetOverride { function_name, mode, expiry, reason, result } => {
+                    let until = expiry.map(|expiry| SystemTime::now() + expiry);
+                    let override_result = bundle
+                        .lock()
+                        .unwrap()
+                        .set_override(&function_name, Override::new(mode, until, reason.clone()));
+                    if override_result.is_ok() {
+                        info!("Override of {function_name} set to {mode:?}: {reason}");
+                        // Reply without waiting for BIRD, which may take several retries
+                        let _ = update_tx.try_send(());
+                    }
+                    let _ = result.send(override_result);
+                }
+            },
+            () = sleep_until(next_override_expiry) => {
+                let expired = bundle.lock().unwrap().expire_overrides(SystemTime::now());
+                if expired {
+                    info!("Override expired");
+                    let _ = update_tx.try_send(());
+                }
+            }
+            _ = sigterm.recv() => {
+                info!("SIGTERM received, shutting down");
+                break;
+            }
+            _ = sigint.recv() => {
+                info!("SIGINT received, shutting down");
+                break;
+            }
+        }
+    }
+    shutdown(bundle, bird_updater, services, original_generated_files).await;
+    Ok(())
+}
+
+/// Stop all the tasks, apply the `shutdown.action` of the config, then remove the PID file and the socket
+async fn shutdown(
+    bundle: &std::sync::Mutex<Bundle>,
+    bird_updater: &BirdUpdater,
+    mut services: Services,
+    original_generated_files: HashMap<String, String>,
+) {
+    // Wait for the end of a BIRD update in progress, and prevent any other one
+    let _guard = bird_updater.last_contents.lock().await;
+    services.join_set.shutdown().await;
+
+    let mut bundle = bundle.lock().unwrap().clone();
+    let shutdown = bundle.config.shutdown.clone();
+    let bird_reloaded = match shutdown.action {
+        ShutdownAction::Keep => false,
+        ShutdownAction::Withdraw => {
+            info!("Withdrawing all the services");
+            for function_value in &mut bundle.function_values {
+                *function_value = FunctionValue {
+                    value: false,
+                    reason: "birdwatcher is shutting down".to_owned(),
+                };
+            }
+            let mut bird_reloaded = false;
+            for output in &bundle.config.outputs {
+                bird_reloaded |= write_and_reload(output, &render_functions(&bundle, output))
+                    .await
+                    .inspect_err(|e| error!("{}: {e}", output.generated_file.path))
+                    .is_ok();
+            }
+            bird_reloaded
+        }
+        ShutdownAction::Restore => {
+            info!("Restoring the generated files as they were when birdwatcher started");
+            let mut bird_reloaded = false;
+            for output in &bundle.config.outputs {
+                let path = &output.generated_file.path;
+                // Not written before birdwatcher started, or added by a reload of the config
+                let Some(content) = original_generated_files.get(path) else {
+                    continue;
+                };
+                bird_reloaded |= write_and_reload(output, content)
+                    .await
+                    .inspect_err(|e| error!("{path}: {e}"))
+                    .is_ok();
+            }
+            bird_reloaded
+        }
+    };
+    if bird_reloaded && !shutdown.drain_delay.is_zero() {
+        info!(
+            "Waiting {}s before exiting",
+            shutdown.drain_delay.as_secs_f32()
+        );
+        tokio::time::sleep(shutdown.drain_delay).await;
+    }
+
+    for path in [PID_PATH, SOCKET_PATH] {
+        if let Err(e) = fs_err::remove_file(path) {
+            warn!("{e}");
+        }
+    }
+}
+
+/// Never completes if `time` is `None`
+async fn sleep_until(time: Option<SystemTime>) {
+    match time {
+        Some(time) => {
+            tokio::time::sleep(time.duration_since(SystemTime::now()).unwrap_or_default()).await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Load the configuration file again, and apply it without restarting the services which did not change.
+/// If the new configuration is invalid, the current one is kept
+fn reload_config(
+    config_path: &Path,
+    bundle: &Arc<std::sync::Mutex<Bundle>>,
+    bird_updater: &Arc<BirdUpdater>,
+    update_tx: &tokio::sync::mpsc::Sender<()>,
+    services: &mut Services,
+) -> Result<()> {
+    let config = Config::load_from_file(config_path).wrap_err(format!(
+        "Failed to load config file {}",
+        config_path.display()
+    ))?;
+    let drain_file_path = |config: &Config| config.drain_file.as_ref().map(|d| d.path.clone());
+    let old_drain_file_path = drain_file_path(&bundle.lock().unwrap().config);
+    if drain_file_path(&config) != old_drain_file_path {
+        // Before changing anything, as the new drain file may not be watchable
+        let drain_events = config
+            .drain_file
+            .as_ref()
+            .map(watch_drain_file)
+            .transpose()?;
+        if let Some(drain_watcher) = services.drain_watcher.take() {
+            drain_watcher.abort();
+        }
+        services.drain_watcher = drain_events.map(|events| {
+            spawn_drain_watcher(
+                &mut services.join_set,
+                bundle.clone(),
+                bird_updater.clone(),
+                events,
             )
-        })
-        .join("\n");
+        });
+    }
+    {
+        let mut bundle = bundle.lock().unwrap();
+        let old_definitions = &bundle.config.service_definitions;
+        for old_def in old_definitions {
+            if !config.service_definitions.contains(old_def) {
+                info!("Stopping {}", old_def.function_name);
+                services
+                    .abort_handles
+                    .remove(&old_def.function_name)
+                    .expect("each service has a task")
+                    .abort();
+            }
+        }
+        for def in &config.service_definitions {
+            if !old_definitions.contains(def) {
+                let abort_handle = services.tasks.spawn(&mut services.join_set, def);
+                services
+                    .abort_handles
+                    .insert(def.function_name.clone(), abort_handle);
+            }
+        }
+        *bundle = bundle.reconfigure(config, SystemTime::now());
+        let drained = drain_file_exists(&bundle.config);
+        if bundle.set_drained(drained) {
+            log_drain_status(drained);
+        }
+    }
+    info!("Configuration reloaded");
 
-    let mut f = fs_err::File::create(&config.generated_file.path).unwrap();
-    f.write_all(content.as_bytes()).unwrap();
+    // If an update is already pending, it will use the new configuration
+    let _ = update_tx.try_send(());
+    Ok(())
 }
 
-async fn launch_reload_function(config: &Config) {
-    let reload_command = Command::new(&config.reload_command)
-        .args(&config.reload_command_args)
-        .output();
-    let reload_return_value = timeout(config.reload_timeout, reload_command).await;
-    match reload_return_value {
-        Ok(Ok(o)) => {
-            if o.status.success() {
-                info!("Reload successful");
+/// Watch the directory of the drain file, as the file itself may not exist
+fn watch_drain_file(drain_file: &DrainFile) -> Result<EventStream<[u8; 1024]>> {
+    let directory = match Path::new(&drain_file.path).parent() {
+        Some(directory) if !directory.as_os_str().is_empty() => directory,
+        _ => Path::new("."),
+    };
+    let inotify = Inotify::init().wrap_err("Cannot initialize inotify")?;
+    inotify
+        .watches()
+        .add(
+            directory,
+            WatchMask::CREATE | WatchMask::DELETE | WatchMask::MOVED_FROM | WatchMask::MOVED_TO,
+        )
+        .wrap_err(format!(
+            "Cannot watch {} for the drain file",
+            directory.display()
+        ))?;
+    Ok(inotify.into_event_stream([0; 1024])?)
+}
+
+fn drain_file_exists(config: &Config) -> bool {
+    config
+        .drain_file
+        .as_ref()
+        .is_some_and(|drain_file| Path::new(&drain_file.path).exists())
+}
+
+fn log_drain_status(drained: bool) {
+    if drained {
+        warn!("Drain file created, withdrawing the services");
+    } else {
+        info!("Drain file removed, the services follow their checks again");
+    }
+}
+
+/// Update the BIRD file each time the drain file is created or removed
+fn spawn_drain_watcher(
+    join_set: &mut JoinSet<!>,
+    bundle: Arc<std::sync::Mutex<Bundle>>,
+    bird_updater: Arc<BirdUpdater>,
+    mut events: EventStream<[u8; 1024]>,
+) -> AbortHandle {
+    join_set.spawn(async move {
+        loop {
+            // Any event in the directory may be about the drain file
+            events
+                .next()
+                .await
+                .expect("an inotify stream never ends")
+                .expect("Cannot read inotify events");
+            let changed = {
+                let mut bundle = bundle.lock().unwrap();
+                let drained = drain_file_exists(&bundle.config);
+                let changed = bundle.set_drained(drained);
+                if changed {
+                    log_drain_status(drained);
+                }
+                changed
+            };
+            if changed {
+                bird_updater.update(&bundle).await;
+            }
+        }
+    })
+}
+
+/// Writes the generated files and reloads BIRD. Only one update runs at a time
+struct BirdUpdater {
+    /// What has last been written in each generated file, by path. Missing until the first write
+    last_contents: tokio::sync::Mutex<HashMap<String, String>>,
+    reloads: opentelemetry::metrics::Counter<u64>,
+    skipped_reloads: opentelemetry::metrics::Counter<u64>,
+    validation_failures: opentelemetry::metrics::Counter<u64>,
+    reload_failures: opentelemetry::metrics::Counter<u64>,
+    /// Incremented by each successful reload, by path of the generated file, so that a verification can tell whether BIRD has been reloaded since
+    generations: std::sync::Mutex<HashMap<String, u64>>,
+    export_mismatches: opentelemetry::metrics::Gauge<u64>,
+}
+
+impl BirdUpdater {
+    fn new(meter: &opentelemetry::metrics::Meter) -> BirdUpdater {
+        BirdUpdater {
+            last_contents: tokio::sync::Mutex::new(HashMap::new()),
+            reloads: meter
+                .u64_counter("birdwatcher_bird_reloads")
+                .with_description(
+                    "Number of times the generated file has been written and BIRD reloaded",
+                )
+                .build(),
+            skipped_reloads: meter
+                .u64_counter("birdwatcher_bird_reloads_skipped")
+                .with_description(
+                    "Number of updates skipped because the generated file would not change",
+                )
+                .build(),
+            validation_failures: meter
+                .u64_counter("birdwatcher_bird_validation_failures")
+                .with_description("Number of generated files refused by the validate command")
+                .build(),
+            reload_failures: meter
+                .u64_counter("birdwatcher_bird_reload_failures")
+                .with_description("Number of reloads of BIRD which failed after all their retries")
+                .build(),
+            generations: std::sync::Mutex::new(HashMap::new()),
+            export_mismatches: meter
+                .u64_gauge("birdwatcher_bird_export_mismatches")
+                .with_description("Number of prefixes of a service which BIRD exports while its function returns false, or the opposite")
+                .build(),
+        }
+    }
+
+    /// Regenerate the BIRD files from the current state of the services, and reload the BIRD of each file whose content changed.
+    /// Taking the lock before the state makes sure the last write is done from the latest state
+    async fn update(self: &Arc<Self>, bundle: &Arc<std::sync::Mutex<Bundle>>) {
+        let mut last_contents = self.last_contents.lock().await;
+        let bundle_copy = bundle.lock().unwrap().clone();
+        // A file removed from the config and added back later must be written again
+        last_contents.retain(|path, _| bundle_copy.output_id(path).is_some());
+        for output in &bundle_copy.config.outputs {
+            let path = &output.generated_file.path;
+            self.update_output(bundle, &bundle_copy, output, &mut last_contents)
+                .instrument(tracing::info_span!("bird_update", generated_file = %path))
+                .await;
+        }
+    }
+
+    async fn update_output(
+        self: &Arc<Self>,
+        bundle: &Arc<std::sync::Mutex<Bundle>>,
+        expected: &Bundle,
+        output: &Output,
+        last_contents: &mut HashMap<String, String>,
+    ) {
+        let path = &output.generated_file.path;
+        let attributes = [KeyValue::new("generated_file", path.clone())];
+        let content = render_functions(expected, output);
+        if last_contents.get(path) == Some(&content) {
+            debug!("Generated file unchanged, BIRD is not reloaded");
+            self.skipped_reloads.add(1, &attributes);
+            return;
+        }
+        let result = write_and_reload(output, &content).await;
+        match &result {
+            Ok(()) => self.spawn_verification(bundle, expected.clone(), output.clone()),
+            Err(e) => error!("{e}"),
+        }
+        let (validation_error, last_reload) = match result {
+            Ok(()) => {
+                self.reloads.add(1, &attributes);
+                last_contents.insert(path.clone(), content);
+                (None, Some(None))
+            }
+            // `last_contents` is left as is, so that the next update tries again
+            Err(UpdateFailure::Write(_)) => return,
+            Err(UpdateFailure::Validation(e)) => {
+                self.validation_failures.add(1, &attributes);
+                (Some(e), None)
+            }
+            Err(UpdateFailure::Reload(e)) => {
+                self.reload_failures.add(1, &attributes);
+                // The generated file has changed but BIRD may not use it. The next update must write and reload again
+                last_contents.remove(path);
+                (None, Some(Some(e)))
+            }
+        };
+        let mut bundle = bundle.lock().unwrap();
+        // The config may have been reloaded in the meantime, without this generated file
+        let Some(id) = bundle.output_id(path) else {
+            return;
+        };
+        let status = &mut bundle.outputs[id];
+        status.validation_error = validation_error;
+        // A refused file leaves BIRD as it was after the last reload
+        if let Some(reload_error) = last_reload {
+            status.last_reload = Some(ReloadStatus {
+                at: SystemTime::now(),
+                error: reload_error,
+            });
+        }
+    }
+
+    /// Once BIRD had time to apply the reload, compare the routes it exports with the function values of `expected`.
+    /// The result is dropped if BIRD has been reloaded again in the meantime
+    fn spawn_verification(
+        self: &Arc<Self>,
+        bundle: &Arc<std::sync::Mutex<Bundle>>,
+        expected: Bundle,
+        output: Output,
+    ) {
+        let path = output.generated_file.path.clone();
+        let generation = {
+            let mut generations = self.generations.lock().unwrap();
+            let generation = generations.entry(path.clone()).or_default();
+            *generation += 1;
+            *generation
+        };
+        let Some(verify) = output.verify.clone() else {
+            return;
+        };
+        let span = tracing::info_span!("bird_update", generated_file = %path);
+        let bird_updater = self.clone();
+        let bundle = bundle.clone();
+        tokio::spawn(
+            async move {
+                tokio::time::sleep(verify.delay).await;
+                let exported = exported_prefixes(&verify, output.reload.timeout).await;
+                if bird_updater.generations.lock().unwrap().get(&path) != Some(&generation) {
+                    debug!("BIRD has been reloaded during the verification, its result is dropped");
+                    return;
+                }
+                let verification =
+                    Verification::new(&expected, &output, exported, SystemTime::now());
+                bird_updater.report(&expected, &output, &verification);
+                let mut bundle = bundle.lock().unwrap();
+                if let Some(id) = bundle.output_id(&path) {
+                    bundle.outputs[id].verification = Some(verification);
+                }
+            }
+            .instrument(span),
+        );
+    }
+
+    fn report(&self, expected: &Bundle, output: &Output, verification: &Verification) {
+        if let Some(e) = &verification.error {
+            error!("Cannot verify the routes exported by BIRD: {e}");
+            return;
+        }
+        for mismatch in &verification.mismatches {
+            if mismatch.expected {
+                warn!(
+                    "BIRD does not export {} although {} returns true",
+                    mismatch.prefix, mismatch.function_name
+                );
             } else {
-                error!(
-                    "Reload failure. stdout = {}, stderr = {}",
-                    String::from_utf8_lossy(&o.stdout),
-                    String::from_utf8_lossy(&o.stderr)
+                warn!(
+                    "BIRD exports {} although {} returns false",
+                    mismatch.prefix, mismatch.function_name
                 );
             }
         }
-        Ok(Err(e)) => {
-            error!(
-                "Could not launch reload command \'{}\'. e = {}",
-                config.reload_command, e
-            );
+        if verification.mismatches.is_empty() {
+            debug!("BIRD exports the expected routes");
         }
-        Err(_) => {
-            error!("Reload command timed out");
+        for def in &expected.config.service_definitions {
+            if def.prefixes.is_empty() || !output.includes(def) {
+                continue;
+            }
+            let mismatches = verification
+                .mismatches
+                .iter()
+                .filter(|mismatch| mismatch.function_name == def.function_name)
+                .count();
+            self.export_mismatches.record(
+                mismatches as u64,
+                &[
+                    KeyValue::new("service", def.service_name.clone()),
+                    KeyValue::new("generated_file", output.generated_file.path.clone()),
+                ],
+            );
         }
     }
 }
 
-fn start_service_tasks(
+/// Update BIRD when requested by the main task, after waiting the longest `debounce` of the outputs for other transitions.
+/// The requests sent during the wait are handled by the same update.
+/// While the last reload of an output failed, BIRD is also updated every `reconcile_interval` of this output without request
+fn spawn_bird_update_task(
     join_set: &mut JoinSet<!>,
-    config: &Config,
-    tx: &tokio::sync::mpsc::Sender<ServiceCommandResult>,
-    function_return_value: &opentelemetry::metrics::Gauge<u64>,
+    bundle: Arc<std::sync::Mutex<Bundle>>,
+    bird_updater: Arc<BirdUpdater>,
+    mut update_rx: tokio::sync::mpsc::Receiver<()>,
 ) {
-    config
-        .service_definitions
-        .iter()
-        .enumerate()
-        .for_each(|(service_nb, service_def)| {
-            info!("Starting {}", service_def.function_name);
-            let service_def = service_def.clone();
+    join_set.spawn(async move {
+        loop {
+            let reconcile_interval = {
+                let bundle = bundle.lock().unwrap();
+                bundle
+                    .config
+                    .outputs
+                    .iter()
+                    .zip(&bundle.outputs)
+                    .filter(|(_, status)| {
+                        status
+                            .last_reload
+                            .as_ref()
+                            .is_some_and(|reload| reload.error.is_some())
+                    })
+                    .map(|(output, _)| output.reload.reconcile_interval)
+                    .min()
+            };
+            let request = if let Some(reconcile_interval) = reconcile_interval {
+                timeout(reconcile_interval, update_rx.recv()).await
+            } else {
+                Ok(update_rx.recv().await)
+            };
+            match request {
+                Ok(Some(())) => {}
+                Ok(None) => {
+                    // All the senders have been dropped by the shutdown
+                    std::future::pending::<()>().await;
+                }
+                Err(_) => {
+                    info!("The last reload of BIRD failed, reloading again");
+                    bird_updater.update(&bundle).await;
+                    continue;
+                }
+            }
+            let debounce = bundle
+                .lock()
+                .unwrap()
+                .config
+                .outputs
+                .iter()
+                .map(|output| output.reload.debounce)
+                .max()
+                .unwrap_or_default();
+            tokio::time::sleep(debounce).await;
+            while update_rx.try_recv().is_ok() {}
+            bird_updater.update(&bundle).await;
+        }
+    });
+}
 
-            let tx = tx.clone();
+/// Why the generated file has not been updated. BIRD is then not reloaded
+enum UpdateFailure {
+    Write(color_eyre::Report),
+    /// Error of the validate command. The previous generated file has been put back
+    Validation(String),
+    /// Error of the last try of the reload command. The new generated file is in place
+    Reload(String),
+}
 
-            let function_return_value = function_return_value.clone();
+impl std::fmt::Display for UpdateFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UpdateFailure::Write(e) => write!(f, "{e:#}"),
+            UpdateFailure::Validation(e) => write!(
+                f,
+                "The validate command refused the generated file, the previous one is kept. {e}"
+            ),
+            UpdateFailure::Reload(e) => {
+                write!(f, "BIRD may still use the previous generated file. {e}")
+            }
+        }
+    }
+}
 
-            join_set.spawn(async move {
-                loop {
-                    debug!(
-                        "Regen function {}, Launching command {}",
-                        service_def.function_name, service_def.command
-                    );
-                    let command = tokio::process::Command::new(service_def.command.clone())
-                        .args(&service_def.args)
-                        .output();
-
-                    let command_execution_span = tracing::info_span!(
-                        "function_execution",
-                        service_def.command,
-                        result = field::Empty
-                    );
-                    let result = timeout(service_def.command_timeout, command)
-                        .instrument(command_execution_span.clone())
-                        .await;
-
-                    let return_value = match result {
-                        Err(..) => {
-                            info!(service_name = service_def.service_name, "Command timed out");
-                            command_execution_span.record("result", "timeout");
-
-                            false
-                        }
-                        Ok(Ok(o)) => {
-                            let span_result = if o.status.success() {
-                                "success"
-                            } else {
-                                "non-zero status"
-                            };
-                            command_execution_span
-                                .record("result", format!("returned {span_result}"));
-                            o.status.success()
-                        }
-                        Ok(Err(e)) => {
-                            warn!(
-                                service_name = service_def.service_name,
-                                "Could not launch command \'{}\'. e = {}", service_def.command, e
-                            );
-                            command_execution_span.record("result", "error launching command");
-
-                            false
-                        }
-                    };
-                    let return_value_u64 = u64::from(return_value);
-                    function_return_value.record(
-                        return_value_u64,
-                        &[KeyValue::new("service", service_def.service_name.clone())],
-                    );
-                    debug!(
-                        "function name {}, return value {return_value}",
-                        service_def.function_name
-                    );
+/// Write the generated file, check it with the validate command if any, and reload BIRD.
+/// The new content is written to a temporary file, which only replaces the generated file once validated, so BIRD never reads a refused file
+async fn write_and_reload(output: &Output, content: &str) -> Result<(), UpdateFailure> {
+    let generated_file = &output.generated_file;
+    let candidate = write_candidate(generated_file, content).map_err(UpdateFailure::Write)?;
+    if let Some(validate_command) = &output.reload.validate_command {
+        if let Err(e) = launch_validate_command(&output.reload, validate_command, &candidate).await
+        {
+            if let Err(remove_error) = fs_err::remove_file(&candidate) {
+                error!("Cannot remove the refused generated file: {remove_error}");
+            }
+            return Err(UpdateFailure::Validation(e));
+        }
+    }
+    install_candidate(generated_file, &candidate).map_err(UpdateFailure::Write)?;
+    reload_with_retries(&output.reload)
+        .await
+        .map_err(UpdateFailure::Reload)
+}
 
-                    tx.send(ServiceCommandResult {
-                        service_id: service_nb,
-                        success: return_value,
-                    })
-                    .await
-                    .unwrap();
+/// Run the reload command again while it fails, at most `retries` times, waiting longer each time up to `max_retry_backoff`
+async fn reload_with_retries(reload: &Reload) -> Result<(), String> {
+    let mut backoff = reload.retry_backoff;
+    let mut retries = 0;
+    loop {
+        match launch_reload_function(reload).await {
+            Ok(()) => return Ok(()),
+            Err(e) if retries < reload.retries => {
+                warn!("{e}. Retrying in {}s", backoff.as_secs_f32());
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(reload.max_retry_backoff);
+                retries += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Return the error, with the stderr of the command, if the `candidate` generated file is refused.
+/// `{generated_file}` in the arguments is replaced by the path of `candidate`
+async fn launch_validate_command(
+    reload: &Reload,
+    validate_command: &str,
+    candidate: &Path,
+) -> Result<(), String> {
+    let candidate = candidate.to_string_lossy();
+    let command = Command::new(validate_command)
+        .args(
+            reload
+                .validate_command_args
+                .iter()
+                .map(|arg| arg.replace("{generated_file}", &candidate)),
+        )
+        .output();
+    match timeout(reload.timeout, command).await {
+        Ok(Ok(o)) if o.status.success() => {
+            debug!("Generated file validated");
+            Ok(())
+        }
+        Ok(Ok(o)) => Err(format!(
+            "{}, stderr = {}",
+            o.status,
+            String::from_utf8_lossy(&o.stderr).trim_end()
+        )),
+        Ok(Err(e)) => Err(format!(
+            "Could not launch validate command '{validate_command}'. e = {e}"
+        )),
+        Err(_) => Err("Validate command timed out".to_owned()),
+    }
+}
 
-                    tokio::time::sleep(service_def.interval).await;
+async fn launch_reload_function(reload: &Reload) -> Result<(), String> {
+    match &reload.method {
+        BirdReload::Command { command, args } => {
+            let reload_command = Command::new(command).args(args).output();
+            let reload_return_value = timeout(reload.timeout, reload_command).await;
+            match reload_return_value {
+                Ok(Ok(o)) => {
+                    if o.status.success() {
+                        info!("Reload successful");
+                        Ok(())
+                    } else {
+                        Err(format!(
+                            "Reload failure. stdout = {}, stderr = {}",
+                            String::from_utf8_lossy(&o.stdout),
+                            String::from_utf8_lossy(&o.stderr)
+                        ))
+                    }
                 }
-            });
-        });
+                Ok(Err(e)) => Err(format!(
+                    "Could not launch reload command \'{command}\'. e = {e}"
+                )),
+                Err(_) => Err("Reload command timed out".to_owned()),
+            }
+        }
+        BirdReload::Socket { path, configure } => {
+            let configure = bird_control::configure(path, *configure);
+            match timeout(reload.timeout, configure).await {
+                Ok(Ok(message)) => {
+                    info!("Reload successful: {message}");
+                    Ok(())
+                }
+                Ok(Err(e)) => Err(format!("Reload failure. {e:#}")),
+                Err(_) => Err(format!("No reply from the BIRD socket {path}")),
+            }
+        }
+    }
+}
+
+/// The running service tasks
+struct Services {
+    tasks: ServiceTasks,
+    /// Also contains the main task
+    join_set: JoinSet<!>,
+    /// Task of each service, by `function_name`
+    abort_handles: HashMap<String, AbortHandle>,
+    /// Only if the config has a drain file
+    drain_watcher: Option<AbortHandle>,
+}
+
+/// What is needed to start the task of a service
+struct ServiceTasks {
+    tx: tokio::sync::mpsc::Sender<ServiceCommandResult>,
+    function_return_value: opentelemetry::metrics::Gauge<u64>,
+    sub_check_return_value: opentelemetry::metrics::Gauge<u64>,
+}
+
+impl ServiceTasks {
+    /// Start a task running the check of a service periodically
+    fn spawn(&self, join_set: &mut JoinSet<!>, service_def: &ServiceDefinition) -> AbortHandle {
+        info!("Starting {}", service_def.function_name);
+        let service_def = Arc::new(service_def.clone());
+
+        let tx = self.tx.clone();
+
+        let function_return_value = self.function_return_value.clone();
+        let sub_check_return_value = self.sub_check_return_value.clone();
+
+        join_set.spawn(async move {
+            loop {
+                debug!(
+                    "Regen function {}, Launching check {}",
+                    service_def.function_name, service_def.check
+                );
+
+                let check_execution_span = tracing::info_span!(
+                    "function_execution",
+                    check = %service_def.check,
+                    service_def.command = field::Empty,
+                    result = field::Empty
+                );
+                // Kept from the time when every check was a command
+                if let Check::Command(command_check) = &service_def.check {
+                    check_execution_span.record("service_def.command", &command_check.command);
+                }
+                let check_started_at = Instant::now();
+                let outcome = service_def
+                    .check
+                    .run(service_def.command_timeout)
+                    .instrument(check_execution_span.clone())
+                    .await;
+                let duration = check_started_at.elapsed();
+                check_execution_span.record("result", &outcome.reason);
+                match outcome.status {
+                    CheckStatus::Success => {}
+                    CheckStatus::Warning => info!(
+                        service_name = service_def.service_name,
+                        "Check warning: {}", outcome.reason
+                    ),
+                    CheckStatus::Failure => info!(
+                        service_name = service_def.service_name,
+                        "Check failed: {}", outcome.reason
+                    ),
+                }
+
+                function_return_value.record(
+                    status_to_u64(outcome.status),
+                    &[KeyValue::new("service", service_def.service_name.clone())],
+                );
+                for sub_check in &outcome.sub_checks {
+                    sub_check_return_value.record(
+                        status_to_u64(sub_check.outcome.status),
+                        &[
+                            KeyValue::new("service", service_def.service_name.clone()),
+                            KeyValue::new("check", sub_check.name.clone()),
+                        ],
+                    );
+                }
+                debug!(
+                    "function name {}, return value {:?}",
+                    service_def.function_name, outcome.status
+                );
+
+                tx.send(ServiceCommandResult {
+                    service_def: service_def.clone(),
+                    outcome,
+                    duration,
+                })
+                .await
+                .unwrap();
+
+                tokio::time::sleep(service_def.interval).await;
+            }
+        })
+    }
+}
+
+/// Value of the `function_return_value` and `sub_check_return_value` metrics
+fn status_to_u64(status: CheckStatus) -> u64 {
+    match status {
+        CheckStatus::Failure => 0,
+        CheckStatus::Success => 1,
+        CheckStatus::Warning => 2,
+    }
+}
+
+/// Instruments updated by the main task
+struct MainTaskMetrics {
+    service_up: opentelemetry::metrics::Gauge<u64>,
+    service_hysteresis_state: opentelemetry::metrics::Gauge<f64>,
+    dampening_penalty: opentelemetry::metrics::Gauge<f64>,
+}
+
+impl MainTaskMetrics {
+    fn new(meter: &opentelemetry::metrics::Meter) -> MainTaskMetrics {
+        MainTaskMetrics {
+            service_up: meter
+                .u64_gauge("birdwatcher_service_up")
+                .with_description("0 = The service is down. 1 = The service is up")
+                .build(),
+            service_hysteresis_state: meter
+                .f64_gauge("birdwatcher_service_hysteresis_state")
+                .with_description("Like service_up, but more detailed. It aggregates the result the last function_return value.
+        It can take intermediate values between 0 and 1 for a failed service raising, or a successful service failing")
+                .build(),
+            dampening_penalty: meter
+                .f64_gauge("birdwatcher_dampening_penalty")
+                .with_description("Flap dampening penalty of a service. Above the `suppress` threshold, the service is down")
+                .build(),
+        }
+    }
 }
 
 fn start_main_task(
     join_set: &mut JoinSet<!>,
-    config: Arc<Config>,
-    service_states: Arc<std::sync::Mutex<Vec<ServiceState>>>,
+    bundle: Arc<std::sync::Mutex<Bundle>>,
+    update_tx: tokio::sync::mpsc::Sender<()>,
+    started_at: SystemTime,
     rx: tokio::sync::mpsc::Receiver<ServiceCommandResult>,
-    service_up: opentelemetry::metrics::Gauge<u64>,
-    service_hysteresis_state: opentelemetry::metrics::Gauge<f64>,
+    metrics: MainTaskMetrics,
 ) {
     join_set.spawn(async move {
         // Move rx inside this task
@@ -286,72 +1016,123 @@ fn start_main_task(
 
         loop {
             let service_command_result = rx.recv().await.unwrap();
+            let service_def = &service_command_result.service_def;
+            let service_label = [KeyValue::new("service", service_def.service_name.clone())];
 
-            let (service_states_copy, should_reload) = {
-                let mut service_states = service_states.lock().unwrap();
-                let (new_state, should_reload) = service_states[service_command_result.service_id]
-                    .update_with(
-                        service_command_result.success,
-                        &config.service_definitions[service_command_result.service_id],
+            let (saved_states, should_reload) = {
+                let mut bundle = bundle.lock().unwrap();
+                let Some(service_id) = bundle
+                    .config
+                    .service_definitions
+                    .iter()
+                    .position(|def| def == &**service_def)
+                else {
+                    debug!(
+                        "Ignoring a result of {}, removed or changed by a config reload",
+                        service_def.function_name
                     );
+                    continue;
+                };
+                let now = SystemTime::now();
+                let old_state = &bundle.service_states[service_id];
+                let (new_state, mut should_reload) =
+                    old_state.update_with(service_command_result.outcome.status, service_def, now);
+                // Only going up or down is a flap, not becoming degraded
+                let flapped = old_state.is_up() != new_state.is_up();
+                // The decay of the dampening penalty alone is not worth saving: it is computed again on restore
+                let should_save = *old_state != new_state || flapped;
+                if let Some(dampening) = &service_def.dampening {
+                    let dampening_state = &mut bundle.dampening_states[service_id];
+                    let suppression_changed = dampening_state.update(dampening, flapped, now);
+                    if suppression_changed {
+                        info!(
+                            service_name = service_def.service_name,
+                            "Service {} by flap dampening (penalty {:.0})",
+                            if dampening_state.suppressed {
+                                "suppressed"
+                            } else {
+                                "reused"
+                            },
+                            dampening_state.penalty
+                        );
+                    }
+                    metrics
+                        .dampening_penalty
+                        .record(dampening_state.penalty, &service_label);
+                    should_reload |= suppression_changed;
+                }
+                if bundle.in_startup_grace[service_id]
+                    && now.duration_since(started_at).unwrap_or_default()
+                        >= service_def.startup_grace
+                {
+                    info!(
+                        service_name = service_def.service_name,
+                        "End of the startup grace period"
+                    );
+                    bundle.in_startup_grace[service_id] = false;
+                    should_reload = true;
+                }
                 let (service_up_value, service_hysteresis_state_value) = match &new_state {
-                    ServiceState::Failure { nb_of_success } => (
-                        0,
-                        f64::from(*nb_of_success)
-                            / f64::from(
-                                config.service_definitions[service_command_result.service_id].rise,
-                            ),
-                    ),
-                    ServiceState::Success { nb_of_failure } => (
+                    ServiceState::Failure { nb_of_success, .. } => {
+                        (0, f64::from(*nb_of_success) / f64::from(service_def.rise))
+                    }
+                    ServiceState::Success { nb_of_failure, .. } => (
                         1,
-                        1.0 - (f64::from(*nb_of_failure)
-                            / f64::from(
-                                config.service_definitions[service_command_result.service_id].fall,
-                            )),
+                        1.0 - (f64::from(*nb_of_failure) / f64::from(service_def.fall)),
                     ),
                 };
-                service_states[service_command_result.service_id] = new_state;
-
-                service_up.record(
-                    service_up_value,
-                    &[KeyValue::new(
-                        "service",
-                        config.service_definitions[service_command_result.service_id]
-                            .service_name
-                            .clone(),
-                    )],
-                );
-                service_hysteresis_state.record(
-                    service_hysteresis_state_value,
-                    &[KeyValue::new(
-                        "service",
-                        config.service_definitions[service_command_result.service_id]
-                            .service_name
-                            .clone(),
-                    )],
-                );
+                let old_int_value = bundle.int_value(service_id);
+                bundle.service_states[service_id] = new_state;
+                bundle.last_outcomes[service_id] = Some(service_command_result.outcome);
+                bundle.check_durations[service_id] = Some(service_command_result.duration);
+                bundle.update_function_values();
+                // E.g. a MED following the health of the service, without any transition
+                should_reload |= bundle.int_value(service_id) != old_int_value;
 
-                (service_states.clone(), should_reload)
+                metrics.service_up.record(service_up_value, &service_label);
+                metrics
+                    .service_hysteresis_state
+                    .record(service_hysteresis_state_value, &service_label);
+
+                let saved_states = bundle
+                    .config
+                    .state_file
+                    .clone()
+                    .filter(|_| should_save)
+                    .map(|state_file| (state_file, SavedStates::from_bundle(&bundle, now)));
+                (saved_states, should_reload)
             };
 
+            if let Some((state_file, saved_states)) = saved_states {
+                // Writing and syncing the file must not block the other tasks
+                let saved = tokio::task::spawn_blocking(move || {
+                    saved_states.save(Path::new(&state_file.path))
+                })
+                .await
+                .expect("saving the state file does not panic");
+                if let Err(e) = saved {
+                    warn!("Cannot save the state of the services: {e:#}");
+                }
+            }
+
             if should_reload {
-                write_bird_function(&config, &service_states_copy);
-                launch_reload_function(&config).await;
+                // If an update is already pending, it will include this transition
+                let _ = update_tx.try_send(());
             }
         }
     });
 }
 
 fn setup_birdwatcher_cli_server(
-    service_states: Arc<std::sync::Mutex<Vec<ServiceState>>>,
-    config: Arc<Config>,
+    bundle: Arc<std::sync::Mutex<Bundle>>,
+    request_tx: tokio::sync::mpsc::Sender<DaemonRequest>,
 ) -> Result<()> {
     async fn spawn(fut: impl Future<Output = ()> + Send + 'static) {
         debug!("spawning");
         tokio::spawn(fut);
     }
 
-    let pid_path = "/tmp/birdwatcher.pid";
+    let pid_path = PID_PATH;
     match fs_err::read_to_string(pid_path) {
         Ok(stored_pid) => {
             let stored_pid = stored_pid.trim();
@@ -375,7 +1156,7 @@ fn setup_birdwatcher_cli_server(
         }
     }
 
-    let socket_path = "/tmp/birdwatcher.sock";
+    let socket_path = SOCKET_PATH;
     match std::fs::remove_file(socket_path) {
         Ok(()) => {}
         Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
@@ -384,9 +1165,6 @@ fn setup_birdwatcher_cli_server(
 
     let listener = UnixListener::bind(Path::new(socket_path)).unwrap();
 
-    let services_states_for_server = service_states;
-    let config_for_server = config;
-
     let codec_builder = LengthDelimitedCodec::builder();
 
     tokio::spawn(async move {
@@ -396,8 +1174,8 @@ fn setup_birdwatcher_cli_server(
             let transport = tarpc::serde_transport::new(framed, Bincode::default());
 
             let server = InsightServer {
-                service_states: services_states_for_server.clone(),
-                config: config_for_server.clone(),
+                bundle: bundle.clone(),
+                request_tx: request_tx.clone(),
             };
             let fut = BaseChannel::with_defaults(transport)
                 .execute(server.serve())
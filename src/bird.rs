@@ -0,0 +1,542 @@
+//! Render the generated file from the state of the services, as BIRD functions, static routes or prefix sets, and write it.
+
+use std::{
+    fs::Permissions,
+    io::Write as _,
+    os::unix::fs::{MetadataExt, PermissionsExt as _},
+    path::{Path, PathBuf},
+};
+
+use color_eyre::{eyre::Context as _, Result};
+use itertools::Itertools as _;
+use tracing::warn;
+
+use crate::{
+    config::{GeneratedContent, GeneratedFile, Output},
+    service::{Bundle, FunctionValue, OnWarning, ServiceDefinition, ServiceState},
+    template,
+    verify::parse_prefix,
+};
+
+/// Variables available in the templates, besides the custom `variables` of the config
+pub const BUILTIN_VARIABLES: &[&str] = &[
+    "function_name",
+    "service_name",
+    "value",
+    "reason",
+    "return_type",
+    "state",
+    "degraded",
+    "nb_of_success",
+    "nb_of_failure",
+    "fall",
+    "rise",
+    "tags",
+    "prefixes",
+    "int_value",
+];
+
+/// Content of the generated file of `output`, with the services it includes.
+/// The services which are down have no routes, and a prefix set which matches no route.
+/// The same state always renders the same content, so that the daemon can skip rewriting an unchanged file
+///
+/// # Panics
+/// This function will panic if a template uses an unknown variable, which `Config` refuses.
+#[must_use]
+pub fn render_functions(bundle: &Bundle, output: &Output) -> String {
+    let generated_file = &output.generated_file;
+    // Combines the services static definition and their mutable state
+    let services = bundle
+        .config
+        .service_definitions
+        .iter()
+        .zip(&bundle.service_states)
+        .zip(&bundle.function_values)
+        .zip((0..).map(|id| bundle.int_value(id)))
+        .filter(|(((service_def, _), _), _)| output.includes(service_def));
+    services
+        .filter_map(
+            |(((service_def, service_state), function_value), int_value)| {
+                let return_value = function_value.value;
+                let return_type = if generated_file.function_return_type {
+                    "-> bool"
+                } else {
+                    ""
+                };
+                if let Some(template) = service_def
+                    .template
+                    .as_ref()
+                    .or(generated_file.template.as_ref())
+                {
+                    return Some(
+                        template::render(template, |name| {
+                            variable(
+                                name,
+                                generated_file,
+                                service_def,
+                                service_state,
+                                function_value,
+                                return_type,
+                                int_value,
+                            )
+                        })
+                        .expect("The templates are checked when the config is loaded"),
+                    );
+                }
+                let announced = return_value && !service_def.prefixes.is_empty();
+                match generated_file.content {
+                    GeneratedContent::Functions => {}
+                    GeneratedContent::Routes => {
+                        return announced.then(|| {
+                            let routes = service_def
+                                .prefixes
+                                .iter()
+                                .map(|prefix| format!("route {prefix} {};", generated_file.route))
+                                .join("\n");
+                            format!("{routes}\n")
+                        });
+                    }
+                    GeneratedContent::PrefixSets => {
+                        return Some(prefix_set(service_def, function_value, announced));
+                    }
+                }
+                Some(functions(
+                    service_def,
+                    service_state,
+                    return_value,
+                    int_value,
+                    generated_file.function_return_type,
+                ))
+            },
+        )
+        .join("\n")
+}
+
+/// Always defined, so that the filters using it stay valid in `bird.conf`.
+/// When the service is down, the set only contains a prefix which is never routed, as BIRD has no empty prefix set
+fn prefix_set(
+    service_def: &ServiceDefinition,
+    function_value: &FunctionValue,
+    announced: bool,
+) -> String {
+    let function_name = &service_def.function_name;
+    if announced {
+        return format!(
+            "define {function_name}_prefixes = [ {} ];\n",
+            service_def.prefixes.join(", ")
+        );
+    }
+    let is_ipv6 = service_def
+        .prefixes
+        .first()
+        .and_then(|prefix| parse_prefix(prefix))
+        .is_some_and(|(address, _)| address.is_ipv6());
+    let never_routed = if is_ipv6 { "::/128" } else { "0.0.0.0/32" };
+    format!(
+        "# Withdrawn: {}\ndefine {function_name}_prefixes = [ {never_routed} ];\n",
+        function_value.reason
+    )
+}
+
+/// The default content of a service: its BIRD function, followed by the optional `_degraded` and int functions
+fn functions(
+    service_def: &ServiceDefinition,
+    service_state: &ServiceState,
+    return_value: bool,
+    int_value: Option<i32>,
+    function_return_type: bool,
+) -> String {
+    let function_name = &service_def.function_name;
+    let return_type = |r#type: &str| {
+        if function_return_type {
+            format!("-> {type}")
+        } else {
+            String::new()
+        }
+    };
+    let bool_type = return_type("bool");
+    let function = format!(
+        "
+function {function_name}() {bool_type}
+{{
+    return {return_value};
+}}
+",
+    );
+    let degraded_function = (service_def.on_warning == OnWarning::Degraded).then(|| {
+        let degraded = matches!(service_state, ServiceState::Success { degraded: true, .. });
+        format!(
+            "
+function {function_name}_degraded() {bool_type}
+{{
+    return {degraded};
+}}
+",
+        )
+    });
+    let int_function =
+        service_def
+            .int_function
+            .as_ref()
+            .zip(int_value)
+            .map(|(int_function, int_value)| {
+                format!(
+                    "
+function {}() {}
+{{
+    return {int_value};
+}}
+",
+                    int_function.function_name,
+                    return_type("int")
+                )
+            });
+    [Some(function), degraded_function, int_function]
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+/// Value of the variable `name` in the template of a service
+fn variable(
+    name: &str,
+    generated_file: &GeneratedFile,
+    service_def: &ServiceDefinition,
+    service_state: &ServiceState,
+    function_value: &FunctionValue,
+    return_type: &str,
+    int_value: Option<i32>,
+) -> Option<String> {
+    let (state, nb_of_success, nb_of_failure, degraded) = match *service_state {
+        ServiceState::Failure { nb_of_success, .. } => ("down", nb_of_success, 0, false),
+        ServiceState::Success {
+            nb_of_failure,
+            degraded,
+            ..
+        } => ("up", 0, nb_of_failure, degraded),
+    };
+    let value = match name {
+        "function_name" => service_def.function_name.clone(),
+        "service_name" => service_def.service_name.clone(),
+        "value" => function_value.value.to_string(),
+        "reason" => function_value.reason.clone(),
+        "return_type" => return_type.to_owned(),
+        "state" => state.to_owned(),
+        "degraded" => degraded.to_string(),
+        "nb_of_success" => nb_of_success.to_string(),
+        "nb_of_failure" => nb_of_failure.to_string(),
+        "fall" => service_def.fall.to_string(),
+        "rise" => service_def.rise.to_string(),
+        "tags" => service_def.tags.join(","),
+        "prefixes" => service_def.prefixes.join(", "),
+        "int_value" => int_value.map(|value| value.to_string()).unwrap_or_default(),
+        _ => service_def
+            .variables
+            .get(name)
+            .or(generated_file.variables.get(name))?
+            .clone(),
+    };
+    Some(value)
+}
+
+/// Replace the generated file by `content`, through a temporary file renamed over it.
+/// BIRD reads either the old or the new content, even if birdwatcher crashes in the middle
+pub fn write_generated_file(generated_file: &GeneratedFile, content: &str) -> Result<()> {
+    let candidate = write_candidate(generated_file, content)?;
+    install_candidate(generated_file, &candidate)
+}
+
+/// Write `content` next to the generated file, with its mode and owner, without replacing it.
+/// Return the path of the written file, to be checked before `install_candidate`
+pub fn write_candidate(generated_file: &GeneratedFile, content: &str) -> Result<PathBuf> {
+    let path = Path::new(&generated_file.path);
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let result = write_synced(generated_file, path, &tmp_path, content);
+    if result.is_err() {
+        // Best effort, the temporary file may not even exist
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+    result.wrap_err(format!(
+        "Cannot write the generated file {}",
+        tmp_path.display()
+    ))?;
+    Ok(tmp_path)
+}
+
+/// Rename the file written by `write_candidate` over the generated file
+pub fn install_candidate(generated_file: &GeneratedFile, candidate: &Path) -> Result<()> {
+    fs_err::rename(candidate, &generated_file.path).wrap_err(format!(
+        "Cannot write the generated file {}",
+        generated_file.path
+    ))
+}
+
+fn write_synced(
+    generated_file: &GeneratedFile,
+    path: &Path,
+    tmp_path: &Path,
+    content: &str,
+) -> Result<()> {
+    let replaced = std::fs::metadata(path).ok();
+
+    let mut file = fs_err::File::create(tmp_path)?;
+    file.write_all(content.as_bytes())?;
+
+    let mode = generated_file
+        .mode
+        .or(replaced.as_ref().map(|m| m.permissions().mode() & 0o7777));
+    if let Some(mode) = mode {
+        file.set_permissions(Permissions::from_mode(mode))?;
+    }
+    // Only change what differs, so that an unprivileged birdwatcher can still keep its own files
+    let created = file.metadata()?;
+    let uid = generated_file
+        .uid
+        .or(replaced.as_ref().map(MetadataExt::uid))
+        .filter(|&uid| uid != created.uid());
+    let gid = generated_file
+        .gid
+        .or(replaced.as_ref().map(MetadataExt::gid))
+        .filter(|&gid| gid != created.gid());
+    if uid.is_some() || gid.is_some() {
+        if let Err(e) = std::os::unix::fs::fchown(file.file(), uid, gid) {
+            let message = format!(
+                "Cannot change the owner of {} to {uid:?}:{gid:?}",
+                tmp_path.display()
+            );
+            if generated_file.uid.is_some() || generated_file.gid.is_some() {
+                return Err(e).wrap_err(message);
+            }
+            // Only inherited from the replaced file, e.g. created by root while birdwatcher is unprivileged
+            warn!("{message}, the new generated file keeps the owner of birdwatcher: {e}");
+        }
+    }
+
+    file.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::BTreeMap, time::SystemTime};
+
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+
+    use super::{install_candidate, render_functions, write_candidate, write_generated_file};
+    use crate::{
+        config::{Config, GeneratedContent, GeneratedFile, Output},
+        service::{Bundle, FunctionValue, IntFunction, IntSource, ServiceState},
+    };
+
+    #[test]
+    fn render() {
+        let config = Config::load_from_file("example/birdwatcher.conf".as_ref()).unwrap();
+        let mut bundle = Bundle::new(config, SystemTime::UNIX_EPOCH);
+        bundle.service_states[0] = ServiceState::Success {
+            nb_of_failure: 0,
+            degraded: false,
+            last_transition: None,
+        };
+        bundle.update_function_values();
+
+        assert_eq!(
+            render_functions(&bundle, &bundle.config.outputs[0]),
+            indoc! {"
+
+                function match_true() -> bool
+                {
+                    return true;
+                }
+
+
+                function match_false() -> bool
+                {
+                    return false;
+                }
+            "}
+        );
+    }
+
+    #[test]
+    fn render_template() {
+        let config = Config::load_from_file("example/birdwatcher.conf".as_ref()).unwrap();
+        let mut bundle = Bundle::new(config, SystemTime::UNIX_EPOCH);
+        let generated_file = &mut bundle.config.outputs[0].generated_file;
+        generated_file.template = Some(
+            "define {{ function_name }}_up = {{ value }}; # {{ state }} {{ community }}".to_owned(),
+        );
+        generated_file.variables =
+            BTreeMap::from([("community".to_owned(), "(65000, 1)".to_owned())]);
+        let second = &mut bundle.config.service_definitions[1];
+        second.template = Some("# {{ service_name }}: {{ nb_of_success }}/{{ rise }}".to_owned());
+        bundle.service_states[1] = ServiceState::Failure {
+            nb_of_success: 1,
+            last_transition: None,
+        };
+        bundle.update_function_values();
+
+        assert_eq!(
+            render_functions(&bundle, &bundle.config.outputs[0]),
+            indoc! {"
+                define match_true_up = false; # down (65000, 1)
+                # second_service: 1/2"}
+        );
+    }
+
+    #[test]
+    fn render_selected_services() {
+        let config = Config::load_from_file("example/birdwatcher.conf".as_ref()).unwrap();
+        let bundle = Bundle::new(config, SystemTime::UNIX_EPOCH);
+        let output = Output {
+            services: Some(vec!["second_service".to_owned()]),
+            ..bundle.config.outputs[0].clone()
+        };
+
+        assert_eq!(
+            render_functions(&bundle, &output),
+            indoc! {"
+
+                function match_false() -> bool
+                {
+                    return false;
+                }
+            "}
+        );
+    }
+
+    #[test]
+    fn render_int_function() {
+        let config = Config::load_from_file("example/birdwatcher.conf".as_ref()).unwrap();
+        let mut bundle = Bundle::new(config, SystemTime::UNIX_EPOCH);
+        bundle.config.service_definitions[1].int_function = Some(IntFunction {
+            function_name: "match_false_med".to_owned(),
+            source: IntSource::Health,
+            best: 0,
+            worst: 200,
+            step: 1,
+        });
+        bundle.service_states[1] = ServiceState::Success {
+            nb_of_failure: 1,
+            degraded: false,
+            last_transition: None,
+        };
+        bundle.update_function_values();
+        let mut output = bundle.config.outputs[0].clone();
+        output.services = Some(vec!["second_service".to_owned()]);
+
+        assert_eq!(
+            render_functions(&bundle, &output),
+            indoc! {"
+
+                function match_false() -> bool
+                {
+                    return true;
+                }
+
+                function match_false_med() -> int
+                {
+                    return 100;
+                }
+            "}
+        );
+
+        // BIRD < 2.14
+        output.generated_file.function_return_type = false;
+        assert!(render_functions(&bundle, &output).contains("function match_false_med() \n"));
+    }
+
+    #[test]
+    fn render_routes_and_prefix_sets() {
+        let config = Config::load_from_file("example/birdwatcher.conf".as_ref()).unwrap();
+        let mut bundle = Bundle::new(config, SystemTime::UNIX_EPOCH);
+        bundle.config.service_definitions[0].prefixes =
+            vec!["192.0.2.1/32".to_owned(), "192.0.2.2/32".to_owned()];
+        bundle.config.service_definitions[1].prefixes = vec!["192.0.2.3/32".to_owned()];
+        bundle.function_values[0].value = true;
+        let mut output = bundle.config.outputs[0].clone();
+
+        // The second service is down, so it has no routes
+        output.generated_file.content = GeneratedContent::Routes;
+        assert_eq!(
+            render_functions(&bundle, &output),
+            indoc! {"
+                route 192.0.2.1/32 blackhole;
+                route 192.0.2.2/32 blackhole;
+            "}
+        );
+        output.generated_file.route = "via 198.51.100.1".to_owned();
+        assert_eq!(
+            render_functions(&bundle, &output),
+            indoc! {"
+                route 192.0.2.1/32 via 198.51.100.1;
+                route 192.0.2.2/32 via 198.51.100.1;
+            "}
+        );
+
+        output.generated_file.content = GeneratedContent::PrefixSets;
+        assert_eq!(
+            render_functions(&bundle, &output),
+            indoc! {"
+                define match_true_prefixes = [ 192.0.2.1/32, 192.0.2.2/32 ];
+
+                # Withdrawn: check failing
+                define match_false_prefixes = [ 0.0.0.0/32 ];
+            "}
+        );
+
+        bundle.config.service_definitions[0].prefixes = vec!["2001:db8::1/128".to_owned()];
+        bundle.function_values[0] = FunctionValue {
+            value: false,
+            reason: "in startup grace period".to_owned(),
+        };
+        assert!(render_functions(&bundle, &output).starts_with(indoc! {"
+            # Withdrawn: in startup grace period
+            define match_true_prefixes = [ ::/128 ];
+        "}));
+    }
+
+    #[test]
+    fn write_atomically_with_mode() {
+        use std::os::unix::fs::PermissionsExt as _;
+
+        let path =
+            std::env::temp_dir().join(format!("birdwatcher_generated_{}.conf", std::process::id()));
+        let mut generated_file = GeneratedFile {
+            path: path.to_str().unwrap().to_owned(),
+            function_return_type: true,
+            mode: Some(0o640),
+            uid: None,
+            gid: None,
+            template: None,
+            variables: BTreeMap::new(),
+            content: GeneratedContent::Functions,
+            route: "blackhole".to_owned(),
+        };
+        let mode = || std::fs::metadata(&path).unwrap().permissions().mode() & 0o7777;
+
+        write_generated_file(&generated_file, "first").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "first");
+        assert_eq!(mode(), 0o640);
+
+        // The mode of the replaced file is kept
+        generated_file.mode = None;
+        write_generated_file(&generated_file, "second").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "second");
+        assert_eq!(mode(), 0o640);
+        assert!(!std::fs::exists(format!("{}.tmp", path.display())).unwrap());
+
+        // The generated file is only replaced once the candidate is installed
+        let candidate = write_candidate(&generated_file, "third").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "second");
+        assert_eq!(std::fs::read_to_string(&candidate).unwrap(), "third");
+        install_candidate(&generated_file, &candidate).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "third");
+        assert!(!std::fs::exists(&candidate).unwrap());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
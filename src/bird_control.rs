@@ -0,0 +1,268 @@
+//! Talk to BIRD through its control socket, like `birdc` does.
+//!
+//! Each line of a reply starts with a 4 digits code followed by `-` if more lines follow, or by a space on the last line.
+//! A line starting with a space continues the previous one, and one starting with `+` is an asynchronous notification.
+//! Codes 0xxx complete a command successfully, 8xxx and 9xxx are errors.
+
+use color_eyre::{
+    eyre::{bail, eyre, Context as _},
+    Result,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt as _, AsyncWriteExt as _, BufReader},
+    net::UnixStream,
+};
+
+/// The variant of the `configure` command sent to BIRD
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigureMode {
+    /// `configure`: the protocols whose configuration changed are restarted
+    Normal,
+    /// `configure soft`: changes of filters do not restart the protocols, routes are not re-filtered
+    Soft,
+    /// `configure check`: the configuration is only parsed, BIRD keeps the current one
+    Check,
+}
+
+impl ConfigureMode {
+    fn command(self) -> &'static str {
+        match self {
+            ConfigureMode::Normal => "configure",
+            ConfigureMode::Soft => "configure soft",
+            ConfigureMode::Check => "configure check",
+        }
+    }
+}
+
+/// A line of a reply, without its code
+#[derive(Debug, PartialEq)]
+struct Line {
+    /// `None` for the lines continuing the previous one
+    code: Option<u16>,
+    text: String,
+    last: bool,
+}
+
+#[derive(Debug, PartialEq)]
+struct Reply {
+    /// Code of the last line
+    code: u16,
+    lines: Vec<String>,
+}
+
+/// Send the `configure` command to the BIRD listening on `socket_path`.
+/// Return the message of BIRD, e.g. "Reconfigured"
+///
+/// # Errors
+///
+/// Will return `Err` if BIRD cannot be reached, or refuses the configuration
+pub async fn configure(socket_path: &str, mode: ConfigureMode) -> Result<String> {
+    let mut lines = request(socket_path, mode.command()).await?;
+    Ok(lines.pop().unwrap_or_default())
+}
+
+/// Send `command` to the BIRD listening on `socket_path`, e.g. `show route export bgp1`.
+/// Return the lines of the reply, without their codes
+///
+/// # Errors
+///
+/// Will return `Err` if BIRD cannot be reached, or replies with an error
+pub async fn request(socket_path: &str, command: &str) -> Result<Vec<String>> {
+    let stream = UnixStream::connect(socket_path)
+        .await
+        .wrap_err(format!("Cannot connect to the BIRD socket {socket_path}"))?;
+    let mut stream = BufReader::new(stream);
+
+    // BIRD greets each client, e.g. "0001 BIRD 2.15 ready."
+    let hello = read_reply(&mut stream).await?;
+    if hello.code != 1 {
+        bail!(
+            "Unexpected greeting from BIRD: {:04} {}",
+            hello.code,
+            hello.lines.join(" ")
+        );
+    }
+
+    stream.write_all(format!("{command}\n").as_bytes()).await?;
+    let reply = read_reply(&mut stream).await?;
+    match reply.code {
+        0..1000 => Ok(reply.lines),
+        8000..10000 => Err(eyre!(
+            "BIRD error {:04}: {}",
+            reply.code,
+            reply.lines.last().map_or("", String::as_str)
+        )),
+        code => Err(eyre!(
+            "Unexpected reply from BIRD: {code:04} {}",
+            reply.lines.join(" ")
+        )),
+    }
+}
+
+async fn read_reply(stream: &mut BufReader<UnixStream>) -> Result<Reply> {
+    let mut lines = Vec::new();
+    let mut buffer = String::new();
+    loop {
+        buffer.clear();
+        if stream.read_line(&mut buffer).await? == 0 {
+            bail!("BIRD closed the connection");
+        }
+        let Some(line) = parse_line(buffer.trim_end_matches('\n'))? else {
+            continue;
+        };
+        lines.push(line.text);
+        if let (true, Some(code)) = (line.last, line.code) {
+            return Ok(Reply { code, lines });
+        }
+    }
+}
+
+/// `None` for the asynchronous notifications, which are not part of the reply
+fn parse_line(line: &str) -> Result<Option<Line>> {
+    if line.starts_with('+') {
+        return Ok(None);
+    }
+    if let Some(text) = line.strip_prefix(' ') {
+        return Ok(Some(Line {
+            code: None,
+            text: text.to_owned(),
+            last: false,
+        }));
+    }
+    let (code, rest) = line
+        .split_at_checked(4)
+        .ok_or_else(|| eyre!("Invalid line from BIRD: '{line}'"))?;
+    let code = code
+        .parse()
+        .map_err(|_| eyre!("Invalid line from BIRD: '{line}'"))?;
+    let last = match rest.split_at_checked(1) {
+        Some(("-", _)) => false,
+        Some((" ", _)) => true,
+        _ => bail!("Invalid line from BIRD: '{line}'"),
+    };
+    Ok(Some(Line {
+        code: Some(code),
+        text: rest[1..].to_owned(),
+        last,
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use tokio::{
+        io::{AsyncBufReadExt as _, AsyncWriteExt as _, BufReader},
+        net::UnixListener,
+        task::JoinHandle,
+    };
+
+    use super::{configure, parse_line, request, ConfigureMode, Line};
+
+    /// Mimics BIRD: greet the client, then answer its command with `reply`.
+    /// The task returns the command received
+    fn serve(name: &str, reply: &'static str) -> (PathBuf, JoinHandle<String>) {
+        let path =
+            std::env::temp_dir().join(format!("birdwatcher_{name}_{}.ctl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let task = tokio::spawn(async move {
+            let (conn, _) = listener.accept().await.unwrap();
+            let mut conn = BufReader::new(conn);
+            conn.write_all(b"0001 BIRD 2.15 ready.\n").await.unwrap();
+            let mut command = String::new();
+            conn.read_line(&mut command).await.unwrap();
+            conn.write_all(reply.as_bytes()).await.unwrap();
+            command
+        });
+        (path, task)
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            parse_line("0003 Reconfigured").unwrap(),
+            Some(Line {
+                code: Some(3),
+                text: "Reconfigured".to_owned(),
+                last: true,
+            })
+        );
+        assert_eq!(
+            parse_line("0002-Reading configuration from /etc/bird.conf").unwrap(),
+            Some(Line {
+                code: Some(2),
+                text: "Reading configuration from /etc/bird.conf".to_owned(),
+                last: false,
+            })
+        );
+        assert_eq!(
+            parse_line(" \tvia 192.0.2.254 on eth0").unwrap(),
+            Some(Line {
+                code: None,
+                text: "\tvia 192.0.2.254 on eth0".to_owned(),
+                last: false,
+            })
+        );
+        assert_eq!(parse_line("+0001 notification").unwrap(), None);
+        assert!(parse_line("Reconfigured").is_err());
+    }
+
+    #[tokio::test]
+    async fn reconfigured() {
+        let (path, task) = serve(
+            "reconfigured",
+            "0002-Reading configuration from /etc/bird/bird.conf\n0003 Reconfigured\n",
+        );
+
+        let message = configure(path.to_str().unwrap(), ConfigureMode::Soft)
+            .await
+            .unwrap();
+        assert_eq!(message, "Reconfigured");
+        assert_eq!(task.await.unwrap(), "configure soft\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn refused() {
+        let (path, task) = serve(
+            "refused",
+            "0002-Reading configuration from /etc/bird/bird.conf\n8002 /etc/bird/birdwatcher.conf:3:5 syntax error\n",
+        );
+
+        let error = configure(path.to_str().unwrap(), ConfigureMode::Normal)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "BIRD error 8002: /etc/bird/birdwatcher.conf:3:5 syntax error"
+        );
+        assert_eq!(task.await.unwrap(), "configure\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn show_route() {
+        let (path, task) = serve(
+            "show_route",
+            "1007-Table master4:\n 192.0.2.1/32         unicast [static1 12:00:00.000] * (200)\n \tvia 10.0.0.1 on eth0\n0000 \n",
+        );
+
+        let lines = request(path.to_str().unwrap(), "show route export bgp1")
+            .await
+            .unwrap();
+        assert_eq!(
+            lines,
+            [
+                "Table master4:",
+                "192.0.2.1/32         unicast [static1 12:00:00.000] * (200)",
+                "\tvia 10.0.0.1 on eth0",
+                ""
+            ]
+        );
+        assert_eq!(task.await.unwrap(), "show route export bgp1\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,231 @@
+use std::fmt;
+
+use itertools::Itertools as _;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use super::{CheckOutcome, CheckStatus};
+use crate::deser::regex_serde::RegexSerde;
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct CommandCheck {
+    pub command: String,
+    pub args: Vec<String>,
+    pub result_mode: ResultMode,
+    /// Exit codes considered as a success, in `ResultMode::ExitCode`.
+    /// Any other exit code, or being killed by a signal, is a failure
+    pub success_exit_codes: Vec<i32>,
+    pub stdout: OutputMatch,
+    pub stderr: OutputMatch,
+}
+
+/// How the exit code of the command is interpreted
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResultMode {
+    /// Success if the exit code is one of `success_exit_codes`
+    ExitCode,
+    /// Nagios plugin semantics: 0 = OK, 1 = WARNING, 2 = CRITICAL, 3 = UNKNOWN
+    Nagios,
+}
+
+/// Conditions on an output stream of the command, checked after the exit code
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct OutputMatch {
+    /// If set, the check fails if the output does not match
+    pub must_match: Option<RegexSerde>,
+    /// If set, the check fails if the output matches
+    pub must_not_match: Option<RegexSerde>,
+}
+
+impl OutputMatch {
+    /// Return the reason of the failure, if any
+    fn check(&self, stream_name: &str, output: &[u8]) -> Option<String> {
+        let output = String::from_utf8_lossy(output);
+        if let Some(regex) = &self.must_match {
+            if !regex.is_match(&output) {
+                return Some(format!("{stream_name} does not match '{regex}'"));
+            }
+        }
+        if let Some(regex) = &self.must_not_match {
+            if regex.is_match(&output) {
+                return Some(format!("{stream_name} matches '{regex}'"));
+            }
+        }
+        None
+    }
+}
+
+impl CommandCheck {
+    pub(super) async fn run(&self) -> CheckOutcome {
+        let output = tokio::process::Command::new(&self.command)
+            .args(&self.args)
+            .output()
+            .await;
+
+        match output {
+            Ok(o) => {
+                let outcome = match self.result_mode {
+                    ResultMode::ExitCode => {
+                        let exit_code_is_success = o
+                            .status
+                            .code()
+                            .is_some_and(|code| self.success_exit_codes.contains(&code));
+                        if exit_code_is_success {
+                            CheckOutcome::success("returned success")
+                        } else {
+                            CheckOutcome::failure(format!("returned {}", o.status))
+                        }
+                    }
+                    ResultMode::Nagios => nagios_outcome(o.status.code(), &o.stdout),
+                };
+                if outcome.status == CheckStatus::Failure {
+                    return outcome;
+                }
+                if let Some(reason) = self
+                    .stdout
+                    .check("stdout", &o.stdout)
+                    .or_else(|| self.stderr.check("stderr", &o.stderr))
+                {
+                    return CheckOutcome::failure(reason);
+                }
+                outcome
+            }
+            Err(e) => {
+                warn!("Could not launch command \'{}\'. e = {}", self.command, e);
+                CheckOutcome::failure("error launching command")
+            }
+        }
+    }
+}
+
+/// Nagios plugins print a one-line status on their first line of output, use it as the reason
+fn nagios_outcome(exit_code: Option<i32>, stdout: &[u8]) -> CheckOutcome {
+    let stdout = String::from_utf8_lossy(stdout);
+    let status_line = stdout.lines().next().unwrap_or_default().trim();
+    let reason = |state: &str| {
+        if status_line.is_empty() {
+            format!("returned {state}")
+        } else {
+            format!("returned {state}: {status_line}")
+        }
+    };
+    match exit_code {
+        Some(0) => CheckOutcome::success(reason("OK")),
+        Some(1) => CheckOutcome::warning(reason("WARNING")),
+        Some(2) => CheckOutcome::failure(reason("CRITICAL")),
+        Some(3) => CheckOutcome::failure(reason("UNKNOWN")),
+        Some(code) => CheckOutcome::failure(reason(&format!("invalid Nagios status {code}"))),
+        None => CheckOutcome::failure("killed by a signal"),
+    }
+}
+
+impl fmt::Display for CommandCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            std::iter::once(&self.command).chain(&self.args).join(" ")
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CommandCheck, OutputMatch, ResultMode};
+    use crate::{check::CheckStatus, deser::regex_serde::RegexSerde};
+
+    fn shell(script: &str) -> CommandCheck {
+        CommandCheck {
+            command: "/bin/sh".to_owned(),
+            args: vec!["-c".to_owned(), script.to_owned()],
+            result_mode: ResultMode::ExitCode,
+            success_exit_codes: vec![0],
+            stdout: OutputMatch::default(),
+            stderr: OutputMatch::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn exit_codes() {
+        assert!(shell("exit 0").run().await.is_success());
+
+        let outcome = shell("exit 1").run().await;
+        assert!(!outcome.is_success());
+        assert_eq!(outcome.reason, "returned exit status: 1");
+
+        let outcome = CommandCheck {
+            success_exit_codes: vec![0, 1],
+            ..shell("exit 1")
+        }
+        .run()
+        .await;
+        assert!(outcome.is_success(), "{}", outcome.reason);
+    }
+
+    #[tokio::test]
+    async fn output_match() {
+        let vendor_script = "echo 'CRITICAL: disk full'; exit 0";
+
+        let outcome = CommandCheck {
+            stdout: OutputMatch {
+                must_match: Some(RegexSerde::new("^OK").unwrap()),
+                must_not_match: None,
+            },
+            ..shell(vendor_script)
+        }
+        .run()
+        .await;
+        assert!(!outcome.is_success());
+        assert_eq!(outcome.reason, "stdout does not match '^OK'");
+
+        let outcome = CommandCheck {
+            stdout: OutputMatch {
+                must_match: None,
+                must_not_match: Some(RegexSerde::new("CRITICAL").unwrap()),
+            },
+            ..shell(vendor_script)
+        }
+        .run()
+        .await;
+        assert!(!outcome.is_success());
+        assert_eq!(outcome.reason, "stdout matches 'CRITICAL'");
+
+        let outcome = CommandCheck {
+            stderr: OutputMatch {
+                must_match: None,
+                must_not_match: Some(RegexSerde::new(".").unwrap()),
+            },
+            ..shell("echo 'OK'")
+        }
+        .run()
+        .await;
+        assert!(outcome.is_success(), "{}", outcome.reason);
+    }
+
+    #[tokio::test]
+    async fn nagios() {
+        let nagios = |script| CommandCheck {
+            result_mode: ResultMode::Nagios,
+            ..shell(script)
+        };
+
+        let outcome = nagios("echo 'LOAD OK - load average: 0.10'; exit 0")
+            .run()
+            .await;
+        assert_eq!(outcome.status, CheckStatus::Success);
+        assert_eq!(outcome.reason, "returned OK: LOAD OK - load average: 0.10");
+
+        let outcome = nagios("echo 'LOAD WARNING - load average: 5.20'; exit 1")
+            .run()
+            .await;
+        assert_eq!(outcome.status, CheckStatus::Warning);
+        assert_eq!(
+            outcome.reason,
+            "returned WARNING: LOAD WARNING - load average: 5.20"
+        );
+
+        assert_eq!(nagios("exit 2").run().await.status, CheckStatus::Failure);
+        assert_eq!(nagios("exit 3").run().await.status, CheckStatus::Failure);
+    }
+}
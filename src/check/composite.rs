@@ -0,0 +1,196 @@
+use std::{fmt, time::Duration};
+
+use futures::future::join_all;
+use itertools::Itertools as _;
+use serde::{Deserialize, Serialize};
+use tracing::{info_span, Instrument as _};
+
+use super::{Check, CheckOutcome, CheckStatus, SubCheckOutcome};
+
+/// Several checks run concurrently, whose results are combined into one
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct CompositeCheck {
+    pub checks: Vec<SubCheck>,
+    pub combine: Combine,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct SubCheck {
+    /// Unique inside a service. Used in the logs, the telemetry and the TUI
+    pub name: String,
+    /// Never a `Check::Composite`
+    pub check: Check,
+}
+
+/// How many sub-checks must succeed for the composite check to succeed
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum Combine {
+    All,
+    Any,
+    AtLeast(u32),
+}
+
+impl Combine {
+    fn required(self, nb_of_checks: usize) -> usize {
+        match self {
+            Combine::All => nb_of_checks,
+            Combine::Any => 1,
+            Combine::AtLeast(n) => n as usize,
+        }
+    }
+}
+
+impl CompositeCheck {
+    /// Each sub-check gets its own `timeout`, as they run concurrently
+    pub(super) async fn run(&self, timeout: Duration) -> CheckOutcome {
+        let outcomes = join_all(self.checks.iter().map(|sub| {
+            // Boxed because `Check::run` is recursive through `CompositeCheck::run`
+            Box::pin(sub.check.run(timeout)).instrument(info_span!(
+                "sub_check_execution",
+                name = sub.name,
+                check = %sub.check
+            ))
+        }))
+        .await;
+        let sub_checks = self
+            .checks
+            .iter()
+            .zip(outcomes)
+            .map(|(sub, outcome)| SubCheckOutcome {
+                name: sub.name.clone(),
+                outcome,
+            })
+            .collect_vec();
+
+        let count = |status| {
+            sub_checks
+                .iter()
+                .filter(|s| s.outcome.status == status)
+                .count()
+        };
+        let nb_of_success = count(CheckStatus::Success);
+        let nb_of_warning = count(CheckStatus::Warning);
+        let required = self.combine.required(sub_checks.len());
+
+        let not_successful = |status_filter: fn(CheckStatus) -> bool| {
+            sub_checks
+                .iter()
+                .filter(|s| status_filter(s.outcome.status))
+                .map(|s| format!("{}: {}", s.name, s.outcome.reason))
+                .join(", ")
+        };
+        let summary = format!("{nb_of_success}/{} checks succeeded", sub_checks.len());
+        let mut outcome = if nb_of_success >= required {
+            CheckOutcome::success(summary)
+        } else if nb_of_success + nb_of_warning >= required {
+            CheckOutcome::warning(format!(
+                "{summary} ({})",
+                not_successful(|s| s == CheckStatus::Warning)
+            ))
+        } else {
+            CheckOutcome::failure(format!(
+                "{summary} ({})",
+                not_successful(|s| s != CheckStatus::Success)
+            ))
+        };
+        outcome.sub_checks = sub_checks;
+        outcome
+    }
+}
+
+impl fmt::Display for CompositeCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let combine = match self.combine {
+            Combine::All => "all".to_owned(),
+            Combine::Any => "any".to_owned(),
+            Combine::AtLeast(n) => format!("at least {n}"),
+        };
+        write!(
+            f,
+            "{combine} of [{}]",
+            self.checks.iter().map(|c| &c.name).join(", ")
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{Combine, CompositeCheck, SubCheck};
+    use crate::check::{
+        command::{CommandCheck, OutputMatch, ResultMode},
+        Check, CheckStatus,
+    };
+
+    fn shell(name: &str, script: &str) -> SubCheck {
+        SubCheck {
+            name: name.to_owned(),
+            check: Check::Command(CommandCheck {
+                command: "/bin/sh".to_owned(),
+                args: vec!["-c".to_owned(), script.to_owned()],
+                result_mode: ResultMode::Nagios,
+                success_exit_codes: vec![0],
+                stdout: OutputMatch::default(),
+                stderr: OutputMatch::default(),
+            }),
+        }
+    }
+
+    async fn run(combine: Combine) -> crate::check::CheckOutcome {
+        CompositeCheck {
+            checks: vec![
+                shell("http", "exit 0"),
+                shell("disk", "echo 'DISK WARNING - 85% used'; exit 1"),
+                shell("db", "exit 2"),
+            ],
+            combine,
+        }
+        .run(Duration::from_secs(5))
+        .await
+    }
+
+    #[tokio::test]
+    async fn combine() {
+        let outcome = run(Combine::All).await;
+        assert_eq!(outcome.status, CheckStatus::Failure);
+        assert_eq!(
+            outcome.reason,
+            "1/3 checks succeeded (disk: returned WARNING: DISK WARNING - 85% used, db: returned CRITICAL)"
+        );
+        let sub_checks: Vec<_> = outcome
+            .sub_checks
+            .iter()
+            .map(|s| (s.name.as_str(), s.outcome.status))
+            .collect();
+        assert_eq!(
+            sub_checks,
+            [
+                ("http", CheckStatus::Success),
+                ("disk", CheckStatus::Warning),
+                ("db", CheckStatus::Failure)
+            ]
+        );
+
+        assert_eq!(run(Combine::Any).await.status, CheckStatus::Success);
+
+        let outcome = run(Combine::AtLeast(2)).await;
+        assert_eq!(outcome.status, CheckStatus::Warning);
+        assert_eq!(
+            outcome.reason,
+            "1/3 checks succeeded (disk: returned WARNING: DISK WARNING - 85% used)"
+        );
+    }
+
+    #[tokio::test]
+    async fn sub_check_timeout() {
+        let outcome = CompositeCheck {
+            checks: vec![shell("slow", "sleep 5"), shell("fast", "exit 0")],
+            combine: Combine::Any,
+        }
+        .run(Duration::from_millis(100))
+        .await;
+        assert_eq!(outcome.status, CheckStatus::Success);
+        assert_eq!(outcome.sub_checks[0].outcome.reason, "timeout");
+    }
+}
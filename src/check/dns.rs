@@ -0,0 +1,335 @@
+use std::{fmt, net::SocketAddr, str::FromStr};
+
+use color_eyre::eyre::{bail, Context as _, Result};
+use hickory_proto::{
+    op::{Message, Query, ResponseCode},
+    rr::{Name, RecordType},
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncReadExt as _, AsyncWriteExt as _},
+    net::{TcpStream, UdpSocket},
+};
+
+use super::CheckOutcome;
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct DnsCheck {
+    pub server: SocketAddr,
+    pub query_name: String,
+    /// e.g. `A`, `AAAA`, `TXT`
+    pub query_type: String,
+    pub protocol: DnsProtocol,
+    /// The check fails if the response code differs, typically `NOERROR`
+    pub expected_rcode: u16,
+    /// If set, one of the answer records must have this value, written as in a zone file (e.g. `192.0.2.1`)
+    pub expected_answer: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DnsProtocol {
+    Udp,
+    Tcp,
+}
+
+/// Parse a response code written as its mnemonic (`NXDOMAIN`) or as its number (`3`)
+pub(crate) fn parse_rcode(rcode: &str) -> Result<u16> {
+    let code = match rcode.to_ascii_uppercase().as_str() {
+        "NOERROR" => ResponseCode::NoError,
+        "FORMERR" => ResponseCode::FormErr,
+        "SERVFAIL" => ResponseCode::ServFail,
+        "NXDOMAIN" => ResponseCode::NXDomain,
+        "NOTIMP" => ResponseCode::NotImp,
+        "REFUSED" => ResponseCode::Refused,
+        other => match other.parse::<u16>() {
+            Ok(code) => <ResponseCode as From<u16>>::from(code),
+            Err(_) => bail!("Unknown DNS response code '{rcode}'"),
+        },
+    };
+    Ok(code.into())
+}
+
+impl DnsCheck {
+    /// Check that the query name and type can be parsed
+    pub(crate) fn validate(&self) -> Result<()> {
+        Name::from_str(&self.query_name)
+            .wrap_err_with(|| format!("Invalid query name '{}'", self.query_name))?;
+        RecordType::from_str(&self.query_type)
+            .wrap_err_with(|| format!("Invalid query type '{}'", self.query_type))?;
+        Ok(())
+    }
+
+    fn build_query(&self) -> Result<Message> {
+        let mut query = Message::query();
+        query.metadata.recursion_desired = true;
+        query.add_query(Query::query(
+            Name::from_str(&self.query_name)?,
+            RecordType::from_str(&self.query_type)?,
+        ));
+        Ok(query)
+    }
+
+    async fn exchange_udp(&self, query: &[u8]) -> std::io::Result<Vec<u8>> {
+        let local_addr: SocketAddr = if self.server.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0; 16], 0).into()
+        };
+        let socket = UdpSocket::bind(local_addr).await?;
+        socket.connect(self.server).await?;
+        socket.send(query).await?;
+        let mut response = vec![0; 4096];
+        let len = socket.recv(&mut response).await?;
+        response.truncate(len);
+        Ok(response)
+    }
+
+    async fn exchange_tcp(&self, query: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut stream = TcpStream::connect(self.server).await?;
+        // Over TCP, each message is prefixed by its length
+        let len = u16::try_from(query.len()).map_err(std::io::Error::other)?;
+        stream.write_all(&len.to_be_bytes()).await?;
+        stream.write_all(query).await?;
+        let len = stream.read_u16().await?;
+        let mut response = vec![0; usize::from(len)];
+        stream.read_exact(&mut response).await?;
+        Ok(response)
+    }
+
+    pub(super) async fn run(&self) -> CheckOutcome {
+        let query = match self.build_query().and_then(|q| Ok(q.to_vec()?)) {
+            Ok(q) => q,
+            Err(e) => return CheckOutcome::failure(format!("cannot build query: {e}")),
+        };
+        let query_id = u16::from_be_bytes([query[0], query[1]]);
+
+        let response = match self.protocol {
+            DnsProtocol::Udp => self.exchange_udp(&query).await,
+            DnsProtocol::Tcp => self.exchange_tcp(&query).await,
+        };
+        let response = match response {
+            Ok(r) => r,
+            Err(e) => return CheckOutcome::failure(format!("no response: {e}")),
+        };
+        let response = match Message::from_vec(&response) {
+            Ok(r) => r,
+            Err(e) => return CheckOutcome::failure(format!("invalid response: {e}")),
+        };
+        if response.id != query_id {
+            return CheckOutcome::failure("response ID does not match the query");
+        }
+        // The answers may be incomplete, so `expected_answer` could not be trusted
+        if response.metadata.truncation {
+            return CheckOutcome::failure(
+                "truncated response, too large for UDP: use protocol = \"tcp\"",
+            );
+        }
+
+        let expected_rcode = <ResponseCode as From<u16>>::from(self.expected_rcode);
+        if response.response_code != expected_rcode {
+            return CheckOutcome::failure(format!(
+                "unexpected rcode {} (expected {expected_rcode})",
+                response.response_code
+            ));
+        }
+
+        if let Some(expected_answer) = &self.expected_answer {
+            if !response
+                .answers
+                .iter()
+                .any(|record| record.data.to_string() == *expected_answer)
+            {
+                return CheckOutcome::failure(format!("no answer matches '{expected_answer}'"));
+            }
+        }
+
+        CheckOutcome::success(format!("rcode {}", response.response_code))
+    }
+}
+
+impl fmt::Display for DnsCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let protocol = match self.protocol {
+            DnsProtocol::Udp => "udp",
+            DnsProtocol::Tcp => "tcp",
+        };
+        write!(
+            f,
+            "dns {} {} @{} ({protocol})",
+            self.query_name, self.query_type, self.server
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::SocketAddr, str::FromStr as _};
+
+    use hickory_proto::{
+        op::{Message, ResponseCode},
+        rr::{rdata::A, Name, RData, Record},
+    };
+    use tokio::{
+        io::{AsyncReadExt as _, AsyncWriteExt as _},
+        net::{TcpListener, UdpSocket},
+    };
+
+    use super::{parse_rcode, DnsCheck, DnsProtocol};
+
+    /// A tiny authoritative server which only knows `ns.example.com. A 192.0.2.1`,
+    /// and `big.example.com.` whose answer only fits over TCP
+    fn respond(query: &[u8], protocol: DnsProtocol) -> Vec<u8> {
+        let query = Message::from_vec(query).unwrap();
+        let mut response = Message::response(query.id, query.op_code);
+        response.add_queries(query.queries.clone());
+        let name = query.queries[0].name().clone();
+        if name == Name::from_str("ns.example.com.").unwrap() {
+            response.add_answer(Record::from_rdata(
+                name,
+                300,
+                RData::A(A::new(192, 0, 2, 1)),
+            ));
+        } else if name == Name::from_str("big.example.com.").unwrap() {
+            match protocol {
+                DnsProtocol::Udp => response.metadata.truncation = true,
+                DnsProtocol::Tcp => {
+                    for i in 1..=100 {
+                        response.add_answer(Record::from_rdata(
+                            name.clone(),
+                            300,
+                            RData::A(A::new(192, 0, 2, i)),
+                        ));
+                    }
+                }
+            }
+        } else {
+            response.metadata.response_code = ResponseCode::NXDomain;
+        }
+        response.to_vec().unwrap()
+    }
+
+    async fn serve_udp() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 4096];
+            loop {
+                let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+                socket
+                    .send_to(&respond(&buf[..len], DnsProtocol::Udp), peer)
+                    .await
+                    .unwrap();
+            }
+        });
+        address
+    }
+
+    async fn serve_tcp() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let len = stream.read_u16().await.unwrap();
+                let mut query = vec![0; usize::from(len)];
+                stream.read_exact(&mut query).await.unwrap();
+                let response = respond(&query, DnsProtocol::Tcp);
+                stream
+                    .write_all(&u16::try_from(response.len()).unwrap().to_be_bytes())
+                    .await
+                    .unwrap();
+                stream.write_all(&response).await.unwrap();
+            }
+        });
+        address
+    }
+
+    fn dns_check(server: SocketAddr, query_name: &str) -> DnsCheck {
+        DnsCheck {
+            server,
+            query_name: query_name.to_owned(),
+            query_type: "A".to_owned(),
+            protocol: DnsProtocol::Udp,
+            expected_rcode: parse_rcode("NOERROR").unwrap(),
+            expected_answer: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn expected_answer() {
+        let server = serve_udp().await;
+
+        let outcome = DnsCheck {
+            expected_answer: Some("192.0.2.1".to_owned()),
+            ..dns_check(server, "ns.example.com.")
+        }
+        .run()
+        .await;
+        assert!(outcome.is_success(), "{}", outcome.reason);
+
+        let outcome = DnsCheck {
+            expected_answer: Some("192.0.2.2".to_owned()),
+            ..dns_check(server, "ns.example.com.")
+        }
+        .run()
+        .await;
+        assert!(!outcome.is_success());
+        assert_eq!(outcome.reason, "no answer matches '192.0.2.2'");
+    }
+
+    #[tokio::test]
+    async fn unexpected_rcode() {
+        let server = serve_udp().await;
+
+        let outcome = dns_check(server, "www.example.com.").run().await;
+        assert!(!outcome.is_success());
+        assert_eq!(
+            outcome.reason,
+            "unexpected rcode Non-Existent Domain (expected No Error)"
+        );
+
+        let outcome = DnsCheck {
+            expected_rcode: parse_rcode("nxdomain").unwrap(),
+            ..dns_check(server, "www.example.com.")
+        }
+        .run()
+        .await;
+        assert!(outcome.is_success(), "{}", outcome.reason);
+    }
+
+    #[tokio::test]
+    async fn tcp() {
+        let server = serve_tcp().await;
+
+        let outcome = DnsCheck {
+            protocol: DnsProtocol::Tcp,
+            expected_answer: Some("192.0.2.1".to_owned()),
+            ..dns_check(server, "ns.example.com.")
+        }
+        .run()
+        .await;
+        assert!(outcome.is_success(), "{}", outcome.reason);
+
+        let outcome = DnsCheck {
+            protocol: DnsProtocol::Tcp,
+            expected_answer: Some("192.0.2.100".to_owned()),
+            ..dns_check(server, "big.example.com.")
+        }
+        .run()
+        .await;
+        assert!(outcome.is_success(), "{}", outcome.reason);
+    }
+
+    #[tokio::test]
+    async fn truncated_udp_response() {
+        let server = serve_udp().await;
+
+        let outcome = dns_check(server, "big.example.com.").run().await;
+        assert!(!outcome.is_success());
+        assert_eq!(
+            outcome.reason,
+            r#"truncated response, too large for UDP: use protocol = "tcp""#
+        );
+    }
+}
@@ -0,0 +1,302 @@
+use std::{collections::BTreeMap, fmt, path::PathBuf, str::FromStr};
+
+use color_eyre::eyre::{bail, eyre, Context as _, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{error_chain, CheckOutcome};
+use crate::deser::regex_serde::RegexSerde;
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct HttpCheck {
+    pub url: String,
+    pub method: String,
+    /// The check fails if the response status is not in any of these ranges
+    pub expected_status: Vec<StatusRange>,
+    /// If set, the check fails if the response body does not match
+    pub body_regex: Option<RegexSerde>,
+    pub headers: BTreeMap<String, String>,
+    /// Set to false to accept invalid TLS certificates
+    pub tls_verify: bool,
+    /// Additional PEM certificate to trust, typically a private CA
+    pub tls_ca_file: Option<PathBuf>,
+    /// Built once from the fields above when the config is loaded
+    #[serde(skip)]
+    pub client: HttpClient,
+}
+
+/// The client sending the requests of an `HttpCheck`.
+/// Not sent to the CLI, where it is left empty
+#[derive(Clone, Default)]
+pub struct HttpClient {
+    client: Option<reqwest::Client>,
+    /// Content of the `tls_ca_file` trusted by the client
+    ca_pem: Option<Vec<u8>>,
+}
+
+impl fmt::Debug for HttpClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(if self.client.is_some() {
+            "HttpClient"
+        } else {
+            "HttpClient(unbuilt)"
+        })
+    }
+}
+
+/// Besides the `tls_ca_file` content, the client only derives from the other fields of the check.
+/// So a config reload restarts a check whose CA file changed, even if its path did not
+impl PartialEq for HttpClient {
+    fn eq(&self, other: &Self) -> bool {
+        self.ca_pem == other.ca_pem
+    }
+}
+
+/// An inclusive range of HTTP status code.
+/// Written `"200-299"`, or `"204"` for a single status code, in the config file
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
+pub struct StatusRange {
+    pub first: u16,
+    pub last: u16,
+}
+
+impl StatusRange {
+    fn contains(self, status: u16) -> bool {
+        (self.first..=self.last).contains(&status)
+    }
+}
+
+impl FromStr for StatusRange {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<StatusRange> {
+        let parse = |code: &str| {
+            code.trim()
+                .parse::<u16>()
+                .wrap_err_with(|| format!("'{code}' is not a valid HTTP status code"))
+        };
+        let (first, last) = s.split_once('-').unwrap_or((s, s));
+        let range = StatusRange {
+            first: parse(first)?,
+            last: parse(last)?,
+        };
+        if range.first > range.last {
+            bail!("Status range '{s}' is empty");
+        }
+        Ok(range)
+    }
+}
+
+impl HttpCheck {
+    /// Check the url, the method and the headers, then build the client with the TLS settings
+    pub(crate) fn validate(&mut self) -> Result<()> {
+        reqwest::Url::parse(&self.url).wrap_err_with(|| format!("Invalid url '{}'", self.url))?;
+        reqwest::Method::from_bytes(self.method.as_bytes())
+            .map_err(|_| eyre!("Invalid HTTP method '{}'", self.method))?;
+        for (name, value) in &self.headers {
+            reqwest::header::HeaderName::from_str(name)
+                .wrap_err_with(|| format!("Invalid HTTP header name '{name}'"))?;
+            reqwest::header::HeaderValue::from_str(value)
+                .wrap_err_with(|| format!("Invalid value for HTTP header '{name}'"))?;
+        }
+        self.client = self.build_client()?;
+        Ok(())
+    }
+
+    fn build_client(&self) -> Result<HttpClient> {
+        let mut builder = reqwest::Client::builder()
+            // Like `curl`, each check should open a new connection and see the raw response
+            .pool_max_idle_per_host(0)
+            .redirect(reqwest::redirect::Policy::none())
+            .danger_accept_invalid_certs(!self.tls_verify);
+        let ca_pem = self.tls_ca_file.as_ref().map(fs_err::read).transpose()?;
+        if let (Some(ca_file), Some(pem)) = (&self.tls_ca_file, &ca_pem) {
+            let certificates = reqwest::Certificate::from_pem_bundle(pem)
+                .wrap_err_with(|| format!("Invalid PEM certificate in {}", ca_file.display()))?;
+            if certificates.is_empty() {
+                bail!("No PEM certificate in {}", ca_file.display());
+            }
+            for certificate in certificates {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+        Ok(HttpClient {
+            client: Some(builder.build().wrap_err("Cannot build the HTTP client")?),
+            ca_pem,
+        })
+    }
+
+    pub(super) async fn run(&self) -> CheckOutcome {
+        let Some(client) = &self.client.client else {
+            return CheckOutcome::failure("HTTP client not built");
+        };
+        let Ok(method) = reqwest::Method::from_bytes(self.method.as_bytes()) else {
+            return CheckOutcome::failure(format!("invalid HTTP method '{}'", self.method));
+        };
+
+        let mut request = client.request(method, &self.url);
+        for (name, value) in &self.headers {
+            request = request.header(name, value);
+        }
+
+        let response = match request.send().await {
+            Ok(r) => r,
+            Err(e) if e.is_connect() => {
+                return CheckOutcome::failure(format!("connect error: {}", error_chain(&e)))
+            }
+            Err(e) => return CheckOutcome::failure(format!("request error: {}", error_chain(&e))),
+        };
+
+        let status = response.status();
+        if !self
+            .expected_status
+            .iter()
+            .any(|range| range.contains(status.as_u16()))
+        {
+            return CheckOutcome::failure(format!("unexpected status {status}"));
+        }
+
+        if let Some(body_regex) = &self.body_regex {
+            match response.text().await {
+                Ok(body) if body_regex.is_match(&body) => {}
+                Ok(_) => {
+                    return CheckOutcome::failure(format!("body does not match '{body_regex}'"))
+                }
+                Err(e) => {
+                    return CheckOutcome::failure(format!("cannot read body: {}", error_chain(&e)))
+                }
+            }
+        }
+
+        CheckOutcome::success(format!("status {status}"))
+    }
+}
+
+impl fmt::Display for HttpCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.method, self.url)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use tokio::{
+        io::{AsyncReadExt as _, AsyncWriteExt as _},
+        net::TcpListener,
+    };
+
+    use super::{HttpCheck, HttpClient, StatusRange};
+    use crate::deser::regex_serde::RegexSerde;
+
+    #[test]
+    fn parse_status_range() {
+        assert_eq!(
+            "200-299".parse::<StatusRange>().unwrap(),
+            StatusRange {
+                first: 200,
+                last: 299
+            }
+        );
+        assert_eq!(
+            "204".parse::<StatusRange>().unwrap(),
+            StatusRange {
+                first: 204,
+                last: 204
+            }
+        );
+        assert!("299-200".parse::<StatusRange>().is_err());
+        assert!("2xx".parse::<StatusRange>().is_err());
+    }
+
+    /// Answer every connection with the same raw HTTP response
+    async fn serve(response: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/health", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (mut conn, _) = listener.accept().await.unwrap();
+                let mut request = [0; 1024];
+                let _ = conn.read(&mut request).await.unwrap();
+                conn.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        url
+    }
+
+    fn http_check(url: String) -> HttpCheck {
+        let mut check = HttpCheck {
+            url,
+            method: "GET".to_owned(),
+            expected_status: vec!["200-299".parse().unwrap()],
+            body_regex: None,
+            headers: BTreeMap::new(),
+            tls_verify: true,
+            tls_ca_file: None,
+            client: HttpClient::default(),
+        };
+        check.validate().unwrap();
+        check
+    }
+
+    #[tokio::test]
+    async fn status_and_body() {
+        let url = serve("HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nOK").await;
+
+        let outcome = http_check(url.clone()).run().await;
+        assert!(outcome.is_success(), "{}", outcome.reason);
+
+        let outcome = HttpCheck {
+            body_regex: Some(RegexSerde::new("^OK$").unwrap()),
+            ..http_check(url.clone())
+        }
+        .run()
+        .await;
+        assert!(outcome.is_success(), "{}", outcome.reason);
+
+        let outcome = HttpCheck {
+            body_regex: Some(RegexSerde::new("CRITICAL").unwrap()),
+            ..http_check(url)
+        }
+        .run()
+        .await;
+        assert!(!outcome.is_success());
+        assert_eq!(outcome.reason, "body does not match 'CRITICAL'");
+    }
+
+    #[tokio::test]
+    async fn unexpected_status() {
+        let url = serve("HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n").await;
+
+        let outcome = http_check(url).run().await;
+        assert!(!outcome.is_success());
+        assert_eq!(outcome.reason, "unexpected status 503 Service Unavailable");
+    }
+
+    #[test]
+    fn invalid_ca_file() {
+        let ca_file =
+            std::env::temp_dir().join(format!("birdwatcher_ca_{}.pem", std::process::id()));
+
+        let mut check = HttpCheck {
+            tls_ca_file: Some(ca_file.clone()),
+            ..http_check("https://127.0.0.1/health".to_owned())
+        };
+        assert!(check.validate().is_err(), "missing CA file should fail");
+
+        std::fs::write(&ca_file, "not a certificate").unwrap();
+        assert!(check.validate().is_err(), "invalid CA file should fail");
+        std::fs::remove_file(&ca_file).unwrap();
+    }
+
+    #[test]
+    fn changed_ca_file_is_a_different_check() {
+        let client = |ca_pem: &[u8]| HttpClient {
+            client: None,
+            ca_pem: Some(ca_pem.to_vec()),
+        };
+        assert_eq!(client(b"first CA"), client(b"first CA"));
+        assert_ne!(client(b"first CA"), client(b"second CA"));
+        assert_ne!(client(b"first CA"), HttpClient::default());
+    }
+}
@@ -0,0 +1,135 @@
+//! The health checks that can be run periodically for a service.
+//!
+//! Each kind of check lives in its own module. They all produce a `CheckOutcome`,
+//! which is then fed to the fall/rise mecanism of `ServiceState`.
+
+pub mod command;
+pub mod composite;
+pub mod dns;
+pub mod http;
+pub mod tcp;
+
+use std::{fmt, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use self::{
+    command::CommandCheck, composite::CompositeCheck, dns::DnsCheck, http::HttpCheck, tcp::TcpCheck,
+};
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub enum Check {
+    /// Run an external executable. The check succeed depending on its exit code, and optionally its output
+    Command(CommandCheck),
+    /// Send an HTTP(S) request, directly from birdwatcher
+    Http(HttpCheck),
+    /// Open a TCP connection, and optionally check what the server answers
+    Tcp(TcpCheck),
+    /// Send a DNS query, and check the response code and optionally the answer
+    Dns(DnsCheck),
+    /// Run several checks concurrently and combine their results
+    Composite(CompositeCheck),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum CheckStatus {
+    Success,
+    /// Only produced by checks with Nagios semantics.
+    /// How it is counted depends on the `on_warning` of the service
+    Warning,
+    Failure,
+}
+
+/// The result of one execution of a check
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CheckOutcome {
+    pub status: CheckStatus,
+    /// Short human-readable explanation of the result, for logs and traces
+    pub reason: String,
+    /// Outcome of each sub-check of a `Check::Composite`, empty for the other checks
+    pub sub_checks: Vec<SubCheckOutcome>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SubCheckOutcome {
+    pub name: String,
+    pub outcome: CheckOutcome,
+}
+
+impl CheckOutcome {
+    pub fn success(reason: impl Into<String>) -> CheckOutcome {
+        CheckOutcome {
+            status: CheckStatus::Success,
+            reason: reason.into(),
+            sub_checks: Vec::new(),
+        }
+    }
+
+    pub fn warning(reason: impl Into<String>) -> CheckOutcome {
+        CheckOutcome {
+            status: CheckStatus::Warning,
+            reason: reason.into(),
+            sub_checks: Vec::new(),
+        }
+    }
+
+    pub fn failure(reason: impl Into<String>) -> CheckOutcome {
+        CheckOutcome {
+            status: CheckStatus::Failure,
+            reason: reason.into(),
+            sub_checks: Vec::new(),
+        }
+    }
+
+    #[must_use]
+    pub fn is_success(&self) -> bool {
+        self.status == CheckStatus::Success
+    }
+}
+
+impl Check {
+    /// Run the check once. A check that does not complete within `timeout` is a failure
+    pub async fn run(&self, timeout: Duration) -> CheckOutcome {
+        if let Check::Composite(c) = self {
+            // The timeout applies to each sub-check, so a slow one is reported by name
+            return c.run(timeout).await;
+        }
+        let outcome = async {
+            match self {
+                Check::Command(c) => c.run().await,
+                Check::Http(c) => c.run().await,
+                Check::Tcp(c) => c.run().await,
+                Check::Dns(c) => c.run().await,
+                Check::Composite(_) => unreachable!("handled above"),
+            }
+        };
+        tokio::time::timeout(timeout, outcome)
+            .await
+            .unwrap_or_else(|_| CheckOutcome::failure("timeout"))
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Check::Command(c) => write!(f, "{c}"),
+            Check::Http(c) => write!(f, "{c}"),
+            Check::Tcp(c) => write!(f, "{c}"),
+            Check::Dns(c) => write!(f, "{c}"),
+            Check::Composite(c) => write!(f, "{c}"),
+        }
+    }
+}
+
+/// Format an error and all its sources on one line.
+/// Useful for errors like `reqwest::Error` whose `Display` omit the root cause (e.g. "connection refused")
+fn error_chain(error: &dyn std::error::Error) -> String {
+    let mut description = error.to_string();
+    let mut source = error.source();
+    while let Some(e) = source {
+        description.push_str(": ");
+        description.push_str(&e.to_string());
+        source = e.source();
+    }
+    description
+}
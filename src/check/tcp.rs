@@ -0,0 +1,160 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncReadExt as _, AsyncWriteExt as _},
+    net::TcpStream,
+};
+
+use super::CheckOutcome;
+use crate::deser::regex_serde::RegexSerde;
+
+/// Stop reading the response after this many bytes if it still does not match
+const MAX_RESPONSE_LEN: usize = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct TcpCheck {
+    /// `host:port` to connect to
+    pub address: String,
+    /// Sent once the connection is established
+    pub send: Option<String>,
+    /// If set, the check fails if what the server sends (e.g. its banner) does not match before the connection is closed
+    pub response_regex: Option<RegexSerde>,
+}
+
+impl TcpCheck {
+    pub(super) async fn run(&self) -> CheckOutcome {
+        let mut stream = match TcpStream::connect(&self.address).await {
+            Ok(s) => s,
+            Err(e) => return CheckOutcome::failure(format!("connect error: {e}")),
+        };
+
+        if let Some(payload) = &self.send {
+            if let Err(e) = stream.write_all(payload.as_bytes()).await {
+                return CheckOutcome::failure(format!("cannot send payload: {e}"));
+            }
+        }
+
+        let Some(response_regex) = &self.response_regex else {
+            return CheckOutcome::success("connected");
+        };
+
+        let mut response = Vec::new();
+        let mut chunk = [0; 4096];
+        loop {
+            match stream.read(&mut chunk).await {
+                Ok(0) => {
+                    return CheckOutcome::failure(format!(
+                        "response does not match '{response_regex}'"
+                    ))
+                }
+                Ok(n) => response.extend_from_slice(&chunk[..n]),
+                Err(e) => return CheckOutcome::failure(format!("cannot read response: {e}")),
+            }
+            if response_regex.is_match(&String::from_utf8_lossy(&response)) {
+                return CheckOutcome::success("response matches");
+            }
+            if response.len() > MAX_RESPONSE_LEN {
+                return CheckOutcome::failure(format!(
+                    "response does not match '{response_regex}' after {MAX_RESPONSE_LEN} bytes"
+                ));
+            }
+        }
+    }
+}
+
+impl fmt::Display for TcpCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "tcp://{}", self.address)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::{
+        io::{AsyncReadExt as _, AsyncWriteExt as _},
+        net::TcpListener,
+    };
+
+    use super::TcpCheck;
+    use crate::deser::regex_serde::RegexSerde;
+
+    /// Mimics a SMTP server: send a banner, then answer `QUIT`
+    async fn serve() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            loop {
+                let (mut conn, _) = listener.accept().await.unwrap();
+                conn.write_all(b"220 mail.example.com ESMTP\r\n")
+                    .await
+                    .unwrap();
+                let mut request = [0; 1024];
+                let n = conn.read(&mut request).await.unwrap();
+                if &request[..n] == b"QUIT\r\n" {
+                    conn.write_all(b"221 Bye\r\n").await.unwrap();
+                }
+            }
+        });
+        address
+    }
+
+    #[tokio::test]
+    async fn connect() {
+        let address = serve().await;
+
+        let outcome = TcpCheck {
+            address,
+            send: None,
+            response_regex: None,
+        }
+        .run()
+        .await;
+        assert!(outcome.is_success(), "{}", outcome.reason);
+    }
+
+    #[tokio::test]
+    async fn send_and_expect() {
+        let address = serve().await;
+
+        let outcome = TcpCheck {
+            address: address.clone(),
+            send: Some("QUIT\r\n".to_owned()),
+            response_regex: Some(RegexSerde::new("(?m)^221 ").unwrap()),
+        }
+        .run()
+        .await;
+        assert!(outcome.is_success(), "{}", outcome.reason);
+
+        let outcome = TcpCheck {
+            address,
+            send: Some("QUIT\r\n".to_owned()),
+            response_regex: Some(RegexSerde::new("^250 ").unwrap()),
+        }
+        .run()
+        .await;
+        assert!(!outcome.is_success());
+        assert_eq!(outcome.reason, "response does not match '^250 '");
+    }
+
+    #[tokio::test]
+    async fn connection_refused() {
+        // Bind then drop the listener to get a port nobody listen on
+        let address = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+
+        let outcome = TcpCheck {
+            address,
+            send: None,
+            response_regex: None,
+        }
+        .run()
+        .await;
+        assert!(!outcome.is_success());
+        assert!(outcome.reason.starts_with("connect error"));
+    }
+}
@@ -2,17 +2,53 @@
 /// It differ from the `elaborated` Config below which use more precise types
 ///  - Use f32 instead of Duration to avoid having to create a `secs` and `nanos` entry for each duration in the TOML file
 ///  - Checks that `command` fields have at least one element, the arg0
+///  - The fields specific to each type of check are flattened in `ServiceDefinition`, and in each `CheckDefinition` of a `composite` service.
+///    The elaborated config groups them in a `Check` and rejects those which do not belong to the chosen `type`.
+///    (`#[serde(flatten)]` cannot be used to share them as it is not compatible with `deny_unknown_fields`)
 mod raw {
+    use std::collections::BTreeMap;
+
     use serde::Deserialize;
 
-    use crate::deser::duration_deser_f32::DurationDeserF32;
+    use crate::{
+        bird_control::ConfigureMode,
+        check::{command::ResultMode, dns::DnsProtocol},
+        config::{GeneratedContent, ShutdownAction},
+        deser::{duration_deser_f32::DurationDeserF32, regex_serde::RegexSerde},
+        service::{InitialState, OnWarning},
+    };
 
     #[derive(Clone, Deserialize)]
     #[serde(deny_unknown_fields)]
     pub struct Config {
+        /// Exclusive with `outputs`
+        pub generated_file: Option<GeneratedFile>,
+        /// Exclusive with `outputs`
+        pub bird_reload: Option<BirdReload>,
+        /// No state file by default
+        pub state_file: Option<StateFile>,
+        /// No drain file by default
+        pub drain_file: Option<DrainFile>,
+        /// What to do when birdwatcher is stopped by SIGTERM or SIGINT
+        pub shutdown: Option<Shutdown>,
+        /// No verification by default. Exclusive with `outputs`
+        pub verify: Option<Verify>,
+        /// Several generated files, e.g. one for each BIRD instance. Exclusive with `generated_file` and `bird_reload`
+        pub outputs: Option<Vec<Output>>,
+        pub service_definitions: Vec<ServiceDefinition>,
+    }
+
+    #[derive(Clone, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct Output {
+        /// `service_name` of the services rendered in this generated file
+        pub services: Option<Vec<String>>,
+        /// Also render the services with one of these tags. All services are rendered if neither `services` nor `tags` is set
+        pub tags: Option<Vec<String>>,
         pub generated_file: GeneratedFile,
         pub bird_reload: BirdReload,
-        pub service_definitions: Vec<ServiceDefinition>,
+        /// No verification by default
+        pub verify: Option<Verify>,
     }
 
     #[derive(Clone, Deserialize)]
@@ -24,13 +60,86 @@ mod raw {
         /// True by default
         /// Turn it off if you use Bird less than 2.14
         pub function_return_type: Option<bool>,
+        /// Mode bits of the file, e.g. `0o640`. Those of the replaced file by default
+        pub mode: Option<u32>,
+        /// Owner of the file. The one of the replaced file by default
+        pub uid: Option<u32>,
+        /// Group of the file. The one of the replaced file by default
+        pub gid: Option<u32>,
+        /// Rendered for each service instead of its BIRD function, e.g. `define {{ function_name }}_up = {{ value }};`
+        pub template: Option<String>,
+        /// Custom variables of the templates
+        pub variables: Option<BTreeMap<String, String>>,
+        /// What is rendered for each service. `functions` by default
+        pub content: Option<GeneratedContent>,
+        /// Appended to the prefix in each static route, e.g. `via 198.51.100.1`. `blackhole` by default
+        pub route: Option<String>,
+    }
+
+    #[derive(Clone, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct StateFile {
+        /// Written after each check, and read when birdwatcher-rs starts
+        pub path: String,
+        /// A state file older than this is ignored. 5 minutes by default
+        pub max_age_s: Option<DurationDeserF32>,
+    }
+
+    #[derive(Clone, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct Verify {
+        /// BIRD protocol whose exported routes are compared to the `prefixes` of the services, e.g. the BGP session to the router
+        pub protocol: String,
+        /// Query BIRD through its control socket. Exclusive with `command`
+        pub socket: Option<String>,
+        /// Run to query BIRD, e.g. `["birdc6"]`, with the query as extra arguments. Exclusive with `socket`.
+        /// By default, the `bird_reload` socket, or its command without the final `configure`
+        pub command: Option<Vec<String>>,
+        /// Time to wait after a reload before querying BIRD. 1 by default
+        pub delay_s: Option<DurationDeserF32>,
+    }
+
+    #[derive(Clone, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct DrainFile {
+        /// While this file exists, the services are withdrawn
+        pub path: String,
+        /// Only drain the services with one of these tags. All services by default
+        pub tags: Option<Vec<String>>,
+    }
+
+    #[derive(Clone, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct Shutdown {
+        /// `keep` by default
+        pub action: Option<ShutdownAction>,
+        /// Time to wait after reloading BIRD, before exiting. 0 by default
+        pub drain_delay_s: Option<DurationDeserF32>,
     }
 
     #[derive(Clone, Deserialize)]
     #[serde(deny_unknown_fields)]
     pub struct BirdReload {
-        pub command: Vec<String>,
+        /// Run to reload BIRD, e.g. `["birdc", "configure"]`. Exclusive with `socket`
+        pub command: Option<Vec<String>>,
+        /// Control socket of BIRD, to send it the `configure` command directly. Exclusive with `command`
+        pub socket: Option<String>,
+        /// Only with `socket`. `normal` by default
+        pub configure: Option<ConfigureMode>,
         pub timeout_s: DurationDeserF32,
+        /// Time to wait for other transitions before reloading BIRD. 0 by default
+        pub debounce_s: Option<DurationDeserF32>,
+        /// Checks the new generated file before it replaces the previous one, e.g. `["bird", "-p", "-c", "/etc/bird/validate.conf"]`.
+        /// `{generated_file}` in the arguments is replaced by the path of the new file. No validation by default
+        pub validate_command: Option<Vec<String>>,
+        /// Number of times a failed reload command is run again. 2 by default
+        pub retries: Option<u32>,
+        /// Time to wait before the first retry, doubled after each retry. 1 by default
+        pub retry_backoff_s: Option<DurationDeserF32>,
+        /// The time between two retries does not grow past this. 30 by default
+        pub max_retry_backoff_s: Option<DurationDeserF32>,
+        /// How often BIRD is reloaded again while the last reload failed. 30 by default
+        pub reconcile_interval_s: Option<DurationDeserF32>,
     }
 
     #[derive(Clone, Deserialize)]
@@ -40,38 +149,434 @@ mod raw {
         pub service_name: String,
         /// This is the BIRD function that you should call in you bird.conf
         pub function_name: String,
-        pub command: Vec<String>,
+        /// The kind of check to run. `command` by default
+        #[serde(rename = "type")]
+        pub check_type: Option<CheckType>,
         pub interval_s: DurationDeserF32,
+        /// Timeout of the check, whatever its `type`
         pub command_timeout_s: DurationDeserF32,
         /// Number of consecutive failure to consider the service unhealthy
         pub fall: u32,
         /// Number of consecutive failure to consider the service healthy
         pub rise: u32,
+        /// Minimum time the service stays up once up. 0 by default
+        pub min_up_time_s: Option<DurationDeserF32>,
+        /// Minimum time the service stays down once down. 0 by default
+        pub min_down_time_s: Option<DurationDeserF32>,
+        /// `rise_once` by default
+        pub initial_state: Option<InitialState>,
+        /// 0 by default
+        pub startup_grace_s: Option<DurationDeserF32>,
+        /// How a WARNING of a Nagios check is counted. `failure` by default
+        pub on_warning: Option<OnWarning>,
+        /// `service_name` of the services which must be up for this one to be up
+        pub depends_on: Option<Vec<String>>,
+        /// Used to select the services withdrawn by the drain file, and those rendered by `outputs[].tags`
+        pub tags: Option<Vec<String>>,
+        /// Routes announced thanks to this service, e.g. `["192.0.2.1/32"]`. Used by `verify`
+        pub prefixes: Option<Vec<String>>,
+        /// Overrides the `template` of the generated file for this service
+        pub template: Option<String>,
+        /// Custom variables of the template. They override those of the generated file
+        pub variables: Option<BTreeMap<String, String>>,
+        /// An additional BIRD function returning an int, e.g. a MED. None by default
+        pub int_function: Option<IntFunction>,
+        /// No flap dampening by default
+        pub dampening: Option<Dampening>,
+
+        // `composite` check
+        pub checks: Option<Vec<CheckDefinition>>,
+        /// `all` by default
+        pub combine: Option<Combine>,
+        pub at_least: Option<u32>,
+
+        // `command` check
+        pub command: Option<Vec<String>>,
+        /// `exit_code` by default
+        pub result_mode: Option<ResultMode>,
+        /// `[0]` by default
+        pub success_exit_codes: Option<Vec<i32>>,
+        pub stdout_regex: Option<RegexSerde>,
+        pub stdout_not_regex: Option<RegexSerde>,
+        pub stderr_regex: Option<RegexSerde>,
+        pub stderr_not_regex: Option<RegexSerde>,
+
+        // `http` check
+        pub url: Option<String>,
+        /// `GET` by default
+        pub method: Option<String>,
+        /// `["200-299"]` by default
+        pub expected_status: Option<Vec<String>>,
+        pub body_regex: Option<RegexSerde>,
+        pub headers: Option<BTreeMap<String, String>>,
+        /// True by default
+        pub tls_verify: Option<bool>,
+        pub tls_ca_file: Option<String>,
+
+        // `tcp` check
+        /// `host:port`
+        pub address: Option<String>,
+        pub send: Option<String>,
+        pub response_regex: Option<RegexSerde>,
+
+        // `dns` check
+        /// `ip` or `ip:port`
+        pub server: Option<String>,
+        pub query_name: Option<String>,
+        /// `A` by default
+        pub query_type: Option<String>,
+        /// `udp` by default
+        pub protocol: Option<DnsProtocol>,
+        /// `NOERROR` by default
+        pub expected_rcode: Option<String>,
+        pub expected_answer: Option<String>,
+    }
+
+    /// BGP-style flap dampening. All fields are optional, the defaults are those of BGP
+    #[derive(Clone, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct Dampening {
+        /// 1000 by default
+        pub penalty: Option<f64>,
+        /// 15 minutes by default
+        pub half_life_s: Option<DurationDeserF32>,
+        /// 2000 by default
+        pub suppress: Option<f64>,
+        /// 750 by default
+        pub reuse: Option<f64>,
+        /// 12000 by default, i.e. a service is suppressed for at most 1 hour with the default `half_life_s` and `reuse`
+        pub max_penalty: Option<f64>,
+    }
+
+    #[derive(Clone, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct IntFunction {
+        pub function_name: String,
+        /// `health` by default
+        pub source: Option<IntSource>,
+        pub best: i32,
+        pub worst: i32,
+        /// 1 by default with `source = "health"`, a tenth of the range between `best` and `worst` with `source = "latency"`
+        pub step: Option<u32>,
+        /// Only with `source = "latency"`, which requires them
+        pub best_latency_s: Option<DurationDeserF32>,
+        pub worst_latency_s: Option<DurationDeserF32>,
+    }
+
+    #[derive(Clone, Copy, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum IntSource {
+        Health,
+        Latency,
+    }
+
+    /// A sub-check of a `composite` service
+    #[derive(Clone, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct CheckDefinition {
+        /// Identify the sub-check in logs, telemetry and `birdwatcher-cli`
+        pub name: String,
+        #[serde(rename = "type")]
+        pub check_type: Option<CheckType>,
+
+        // `command` check
+        pub command: Option<Vec<String>>,
+        /// `exit_code` by default
+        pub result_mode: Option<ResultMode>,
+        /// `[0]` by default
+        pub success_exit_codes: Option<Vec<i32>>,
+        pub stdout_regex: Option<RegexSerde>,
+        pub stdout_not_regex: Option<RegexSerde>,
+        pub stderr_regex: Option<RegexSerde>,
+        pub stderr_not_regex: Option<RegexSerde>,
+
+        // `http` check
+        pub url: Option<String>,
+        /// `GET` by default
+        pub method: Option<String>,
+        /// `["200-299"]` by default
+        pub expected_status: Option<Vec<String>>,
+        pub body_regex: Option<RegexSerde>,
+        pub headers: Option<BTreeMap<String, String>>,
+        /// True by default
+        pub tls_verify: Option<bool>,
+        pub tls_ca_file: Option<String>,
+
+        // `tcp` check
+        /// `host:port`
+        pub address: Option<String>,
+        pub send: Option<String>,
+        pub response_regex: Option<RegexSerde>,
+
+        // `dns` check
+        /// `ip` or `ip:port`
+        pub server: Option<String>,
+        pub query_name: Option<String>,
+        /// `A` by default
+        pub query_type: Option<String>,
+        /// `udp` by default
+        pub protocol: Option<DnsProtocol>,
+        /// `NOERROR` by default
+        pub expected_rcode: Option<String>,
+        pub expected_answer: Option<String>,
+    }
+
+    impl ServiceDefinition {
+        /// The check defined directly in the service
+        pub fn check_definition(&self) -> CheckDefinition {
+            CheckDefinition {
+                name: self.service_name.clone(),
+                check_type: self.check_type,
+                command: self.command.clone(),
+                result_mode: self.result_mode,
+                success_exit_codes: self.success_exit_codes.clone(),
+                stdout_regex: self.stdout_regex.clone(),
+                stdout_not_regex: self.stdout_not_regex.clone(),
+                stderr_regex: self.stderr_regex.clone(),
+                stderr_not_regex: self.stderr_not_regex.clone(),
+                url: self.url.clone(),
+                method: self.method.clone(),
+                expected_status: self.expected_status.clone(),
+                body_regex: self.body_regex.clone(),
+                headers: self.headers.clone(),
+                tls_verify: self.tls_verify,
+                tls_ca_file: self.tls_ca_file.clone(),
+                address: self.address.clone(),
+                send: self.send.clone(),
+                response_regex: self.response_regex.clone(),
+                server: self.server.clone(),
+                query_name: self.query_name.clone(),
+                query_type: self.query_type.clone(),
+                protocol: self.protocol,
+                expected_rcode: self.expected_rcode.clone(),
+                expected_answer: self.expected_answer.clone(),
+            }
+        }
+    }
+
+    #[derive(Clone, Copy, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum Combine {
+        All,
+        Any,
+    }
+
+    #[derive(Clone, Copy, PartialEq, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum CheckType {
+        Command,
+        Http,
+        Tcp,
+        Dns,
+        Composite,
+    }
+
+    impl CheckType {
+        pub fn name(self) -> &'static str {
+            match self {
+                CheckType::Command => "command",
+                CheckType::Http => "http",
+                CheckType::Tcp => "tcp",
+                CheckType::Dns => "dns",
+                CheckType::Composite => "composite",
+            }
+        }
     }
 }
 
 use color_eyre::{
-    eyre::{Context as _, ContextCompat},
+    eyre::{bail, Context as _, ContextCompat},
     Result,
 };
 use serde::{Deserialize, Serialize};
-use std::{path::Path, time::Duration};
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    time::Duration,
+};
 
-use crate::service::ServiceDefinition;
+use itertools::Itertools as _;
+
+use crate::{
+    bird::BUILTIN_VARIABLES,
+    bird_control::ConfigureMode,
+    check::{
+        command::{CommandCheck, OutputMatch, ResultMode},
+        composite::{Combine, CompositeCheck, SubCheck},
+        dns::{parse_rcode, DnsCheck, DnsProtocol},
+        http::{HttpCheck, HttpClient},
+        tcp::TcpCheck,
+        Check,
+    },
+    service::{
+        service_id, Dampening, InitialState, IntFunction, IntSource, OnWarning, ServiceDefinition,
+    },
+    template,
+    verify::parse_prefix,
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[cfg_attr(test, derive(PartialEq))]
 pub struct GeneratedFile {
     pub path: String,
     pub function_return_type: bool,
+    /// Those of the replaced file if `None`
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// Rendered for each service instead of the default content
+    pub template: Option<String>,
+    pub variables: BTreeMap<String, String>,
+    pub content: GeneratedContent,
+    /// Only used by `GeneratedContent::Routes`
+    pub route: String,
+}
+
+/// What the generated file contains for each service, when it has no template
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GeneratedContent {
+    /// A function returning whether the service is up, to be called by the filters
+    Functions,
+    /// A static route for each prefix of the services which are up, to be included in a `protocol static`
+    Routes,
+    /// A prefix set `<function_name>_prefixes` for each service, with its prefixes when it is up, or a prefix which is never routed
+    PrefixSets,
+}
+
+/// Where the state of the services is saved, to survive a restart of the daemon
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(PartialEq))]
+pub struct StateFile {
+    pub path: String,
+    pub max_age: Duration,
+}
+
+/// After each reload, BIRD is queried to check that it exports the `prefixes` of the services which are up, and only those
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Verify {
+    pub protocol: String,
+    pub query: BirdQuery,
+    /// Time to wait after a reload before querying BIRD
+    pub delay: Duration,
+}
+
+/// How to send a command, like `show route`, to BIRD
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum BirdQuery {
+    /// Through its control socket
+    Socket(String),
+    /// Run an external executable, e.g. `birdc`, with the command as extra arguments
+    Command { command: String, args: Vec<String> },
+}
+
+/// While this file exists, the selected services are withdrawn, whatever their state
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DrainFile {
+    pub path: String,
+    /// Only drain the services with one of these tags. All services if `None`
+    pub tags: Option<Vec<String>>,
 }
 
+impl DrainFile {
+    /// Whether the drain file withdraws this service
+    #[must_use]
+    pub fn drains(&self, service_definition: &ServiceDefinition) -> bool {
+        self.tags
+            .as_ref()
+            .is_none_or(|tags| tags.iter().any(|tag| service_definition.tags.contains(tag)))
+    }
+}
+
+/// What happens to the generated file when birdwatcher is stopped
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShutdownAction {
+    /// Every function returns false, so that BIRD stops announcing services which are no longer monitored
+    Withdraw,
+    /// The generated file is left as it is
+    Keep,
+    /// The generated file gets back the content it had when birdwatcher started.
+    /// It is left as it is if it did not exist
+    Restore,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Shutdown {
+    pub action: ShutdownAction,
+    /// Time to wait after BIRD has been reloaded, to let the withdrawal propagate
+    pub drain_delay: Duration,
+}
+
+/// How BIRD is told to read its configuration again
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub enum BirdReload {
+    /// Run an external executable, e.g. `birdc configure`
+    Command { command: String, args: Vec<String> },
+    /// Send the `configure` command on the control socket of BIRD
+    Socket {
+        path: String,
+        configure: ConfigureMode,
+    },
+}
+
+/// How BIRD is reloaded once the generated file changed
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Reload {
+    pub method: BirdReload,
+    pub timeout: Duration,
+    /// Transitions happening during this time after another one result in a single reload
+    pub debounce: Duration,
+    /// Run with `validate_command_args` on the new generated file, before it replaces the previous one. If it fails, the previous one is kept
+    pub validate_command: Option<String>,
+    pub validate_command_args: Vec<String>,
+    /// A failed reload is tried again up to `retries` times, waiting `retry_backoff`, then twice as long, etc.
+    /// up to `max_retry_backoff`
+    pub retries: u32,
+    pub retry_backoff: Duration,
+    pub max_retry_backoff: Duration,
+    /// While the last reload failed, BIRD is reloaded again every `reconcile_interval`
+    pub reconcile_interval: Duration,
+}
+
+/// A generated file, and how to reload the BIRD instance which includes it
 #[derive(Debug, Clone, Deserialize, Serialize)]
-pub struct Config {
+#[cfg_attr(test, derive(PartialEq))]
+pub struct Output {
     pub generated_file: GeneratedFile,
-    pub reload_command: String,
-    pub reload_command_args: Vec<String>,
-    pub reload_timeout: Duration,
+    pub reload: Reload,
+    pub verify: Option<Verify>,
+    /// `service_name` of the services rendered in the generated file
+    pub services: Option<Vec<String>>,
+    /// The services with one of these tags are also rendered. All services are rendered if both are `None`
+    pub tags: Option<Vec<String>>,
+}
+
+impl Output {
+    /// Whether this service is rendered in the generated file
+    #[must_use]
+    pub fn includes(&self, service_definition: &ServiceDefinition) -> bool {
+        if self.services.is_none() && self.tags.is_none() {
+            return true;
+        }
+        self.services
+            .as_ref()
+            .is_some_and(|services| services.contains(&service_definition.service_name))
+            || self
+                .tags
+                .as_ref()
+                .is_some_and(|tags| tags.iter().any(|tag| service_definition.tags.contains(tag)))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Config {
+    /// Written in order. Most configurations have a single one
+    pub outputs: Vec<Output>,
+    pub state_file: Option<StateFile>,
+    pub drain_file: Option<DrainFile>,
+    pub shutdown: Shutdown,
     pub service_definitions: Vec<ServiceDefinition>,
 }
 
@@ -88,42 +593,776 @@ impl Config {
     fn from_string(str: &str) -> Result<Config> {
         let raw_config: raw::Config = toml::from_str(str)?;
 
-        let (bird_reload_cmd, bird_reload_args) =
-            raw_config.bird_reload.command.split_first().wrap_err("'bird_reload.command' should contain at least one element: the path to the executable to run")?;
+        let outputs = match (
+            raw_config.generated_file,
+            raw_config.bird_reload,
+            raw_config.verify,
+            raw_config.outputs,
+        ) {
+            (Some(generated_file), Some(bird_reload), verify, None) => {
+                vec![elaborate_output(raw::Output {
+                    services: None,
+                    tags: None,
+                    generated_file,
+                    bird_reload,
+                    verify,
+                })?]
+            }
+            (None, None, None, Some(outputs)) if !outputs.is_empty() => outputs
+                .into_iter()
+                .map(elaborate_output)
+                .collect::<Result<_>>()?,
+            _ => bail!("The configuration should define either 'generated_file' and 'bird_reload', or 'outputs'"),
+        };
 
-        Ok(Config {
-            generated_file: GeneratedFile { path: raw_config.generated_file.path, function_return_type: raw_config.generated_file.function_return_type.unwrap_or(true) },
-            reload_command: bird_reload_cmd.to_owned(),
-            reload_command_args: bird_reload_args.to_owned(),
-            reload_timeout: raw_config.bird_reload.timeout_s.into(),
+        let config = Config {
+            outputs,
+            state_file: raw_config.state_file.map(|state_file| StateFile {
+                path: state_file.path,
+                max_age: state_file
+                    .max_age_s
+                    .map_or(Duration::from_mins(5), Into::into),
+            }),
+            drain_file: raw_config.drain_file.map(|drain_file| DrainFile {
+                path: drain_file.path,
+                tags: drain_file.tags,
+            }),
+            shutdown: Shutdown {
+                action: raw_config
+                    .shutdown
+                    .as_ref()
+                    .and_then(|shutdown| shutdown.action)
+                    .unwrap_or(ShutdownAction::Keep),
+                drain_delay: raw_config
+                    .shutdown
+                    .as_ref()
+                    .and_then(|shutdown| shutdown.drain_delay_s)
+                    .map_or(Duration::ZERO, Into::into),
+            },
             service_definitions: raw_config
                 .service_definitions
                 .into_iter()
-                .map(|raw| {
-                    raw.command.split_first().context(format!("'service_definitions.command' of service '{}' should contain at least one element: the path to the executable to run", raw.service_name))
-                    .map(|(cmd, args)  | {
-                        ServiceDefinition {
-                            service_name: raw.service_name,
-                            function_name: raw.function_name,
-                            command: cmd.to_owned(),
-                            args: args.to_owned(),
-                            interval: raw.interval_s.into(),
-                            command_timeout: raw.command_timeout_s.into(),
-                            fall: raw.fall,
-                            rise: raw.rise,
-                        }
-                    })
+                .map(elaborate_service_definition)
+                .collect::<Result<Vec<_>>>()?,
+        };
+        if let Some(function_name) = config
+            .service_definitions
+            .iter()
+            .flat_map(bird_function_names)
+            .duplicates()
+            .next()
+        {
+            bail!("Several services define the BIRD function '{function_name}'");
+        }
+        check_dependencies(&config.service_definitions)?;
+        check_outputs(&config)?;
+        check_templates(&config)?;
+        if let Some(tags) = config.drain_file.as_ref().and_then(|d| d.tags.as_ref()) {
+            if let Some(tag) = tags.iter().find(|tag| {
+                !config
+                    .service_definitions
+                    .iter()
+                    .any(|def| def.tags.contains(tag))
+            }) {
+                bail!("'drain_file.tags' contains '{tag}', which is not a tag of any service");
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// The BIRD functions generated for a service
+fn bird_function_names(def: &ServiceDefinition) -> impl Iterator<Item = String> + '_ {
+    std::iter::once(def.function_name.clone())
+        .chain(
+            (def.on_warning == OnWarning::Degraded)
+                .then(|| format!("{}_degraded", def.function_name)),
+        )
+        .chain(
+            def.int_function
+                .as_ref()
+                .map(|int_function| int_function.function_name.clone()),
+        )
+}
+
+/// The services and tags selected by the outputs must exist, and each output must have its own generated file
+fn check_outputs(config: &Config) -> Result<()> {
+    if let Some(path) = config
+        .outputs
+        .iter()
+        .map(|output| &output.generated_file.path)
+        .duplicates()
+        .next()
+    {
+        bail!("Several outputs write the generated file '{path}'");
+    }
+    for output in &config.outputs {
+        if let Some(service) = output.services.iter().flatten().find(|service| {
+            !config
+                .service_definitions
+                .iter()
+                .any(|def| &def.service_name == *service)
+        }) {
+            bail!("'outputs.services' contains '{service}', which is not a service");
+        }
+        if let Some(tag) = output.tags.iter().flatten().find(|tag| {
+            !config
+                .service_definitions
+                .iter()
+                .any(|def| def.tags.contains(tag))
+        }) {
+            bail!("'outputs.tags' contains '{tag}', which is not a tag of any service");
+        }
+        if output.generated_file.content != GeneratedContent::Functions {
+            // There would be nothing to route, and an empty prefix set is not valid in BIRD
+            if let Some(def) = config
+                .service_definitions
+                .iter()
+                .find(|def| output.includes(def) && def.prefixes.is_empty())
+            {
+                bail!(
+                    "Service '{}' has no 'prefixes', which the generated file '{}' requires",
+                    def.service_name,
+                    output.generated_file.path
+                );
+            }
+        }
+        if output.generated_file.content == GeneratedContent::PrefixSets {
+            // Prefix sets and functions share the namespace of BIRD
+            if let Some((def, prefix_set)) = config
+                .service_definitions
+                .iter()
+                .filter(|def| output.includes(def))
+                .map(|def| (def, format!("{}_prefixes", def.function_name)))
+                .find(|(_, prefix_set)| {
+                    config
+                        .service_definitions
+                        .iter()
+                        .flat_map(bird_function_names)
+                        .contains(prefix_set)
                 })
-                .collect::<Result<Vec<_>, _>>()?,
+            {
+                bail!(
+                    "The prefix set '{prefix_set}' of service '{}' has the name of a BIRD function",
+                    def.service_name
+                );
+            }
+            // A BIRD prefix set is either IPv4 or IPv6
+            if let Some(def) = config.service_definitions.iter().find(|def| {
+                output.includes(def)
+                    && def
+                        .prefixes
+                        .iter()
+                        .filter_map(|prefix| parse_prefix(prefix))
+                        .map(|(address, _)| address.is_ipv4())
+                        .unique()
+                        .count()
+                        > 1
+            }) {
+                bail!(
+                    "'prefixes' of service '{}' mixes IPv4 and IPv6, which a prefix set cannot",
+                    def.service_name
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Each service must only use known variables in its template
+fn check_templates(config: &Config) -> Result<()> {
+    if let Some(name) = config
+        .outputs
+        .iter()
+        .flat_map(|output| output.generated_file.variables.keys())
+        .chain(
+            config
+                .service_definitions
+                .iter()
+                .flat_map(|def| def.variables.keys()),
+        )
+        .find(|name| BUILTIN_VARIABLES.contains(&name.as_str()))
+    {
+        bail!("'variables' cannot redefine the built-in variable '{name}'");
+    }
+    for output in &config.outputs {
+        let generated_file = &output.generated_file;
+        for def in config
+            .service_definitions
+            .iter()
+            .filter(|def| output.includes(def))
+        {
+            let Some(template) = def.template.as_ref().or(generated_file.template.as_ref()) else {
+                continue;
+            };
+            template::render(template, |name| {
+                (BUILTIN_VARIABLES.contains(&name)
+                    || def.variables.contains_key(name)
+                    || generated_file.variables.contains_key(name))
+                .then(String::new)
+            })
+            .wrap_err(format!(
+                "Invalid template for service '{}'",
+                def.service_name
+            ))?;
+        }
+    }
+    Ok(())
+}
+
+fn elaborate_output(raw: raw::Output) -> Result<Output> {
+    let generated_file = raw.generated_file;
+    let content = generated_file
+        .content
+        .unwrap_or(GeneratedContent::Functions);
+    if generated_file.route.is_some() && content != GeneratedContent::Routes {
+        bail!("'generated_file.route' can only be used with 'generated_file.content = \"routes\"'");
+    }
+    let reload = elaborate_reload(&raw.bird_reload)?;
+    let verify = raw
+        .verify
+        .map(|verify| elaborate_verify(verify, &reload.method))
+        .transpose()?;
+    Ok(Output {
+        generated_file: GeneratedFile {
+            path: generated_file.path,
+            function_return_type: generated_file.function_return_type.unwrap_or(true),
+            mode: generated_file.mode,
+            uid: generated_file.uid,
+            gid: generated_file.gid,
+            template: generated_file.template,
+            variables: generated_file.variables.unwrap_or_default(),
+            content,
+            route: generated_file
+                .route
+                .unwrap_or_else(|| "blackhole".to_owned()),
+        },
+        reload,
+        verify,
+        services: raw.services,
+        tags: raw.tags,
+    })
+}
+
+/// Without `socket` nor `command`, BIRD is queried the way it is reloaded, so each output queries its own BIRD
+fn elaborate_verify(raw: raw::Verify, reload: &BirdReload) -> Result<Verify> {
+    let query = match (raw.socket, raw.command, reload) {
+        (Some(_), Some(_), _) => bail!("'verify' should define either 'socket' or 'command', not both"),
+        (Some(socket), None, _) => BirdQuery::Socket(socket),
+        (None, Some(command), _) => {
+            let (command, args) = command.split_first().wrap_err("'verify.command' should contain at least one element: the path to the executable to run")?;
+            BirdQuery::Command {
+                command: command.to_owned(),
+                args: args.to_owned(),
+            }
+        }
+        (None, None, BirdReload::Socket { path, .. }) => BirdQuery::Socket(path.clone()),
+        (None, None, BirdReload::Command { command, args }) => match args.split_last() {
+            Some((last, args)) if last == "configure" => BirdQuery::Command {
+                command: command.clone(),
+                args: args.to_owned(),
+            },
+            _ => bail!("'verify' should define 'socket' or 'command': BIRD cannot be queried with the 'bird_reload.command', which does not end with 'configure'"),
+        },
+    };
+    Ok(Verify {
+        protocol: raw.protocol,
+        query,
+        delay: raw.delay_s.map_or(Duration::from_secs(1), Into::into),
+    })
+}
+
+fn elaborate_reload(raw: &raw::BirdReload) -> Result<Reload> {
+    let (validate_command, validate_command_args) = match &raw.validate_command {
+        None => (None, Vec::new()),
+        Some(validate_command) => {
+            let (cmd, args) = validate_command.split_first().wrap_err("'bird_reload.validate_command' should contain at least one element: the path to the executable to run")?;
+            (Some(cmd.to_owned()), args.to_owned())
+        }
+    };
+    Ok(Reload {
+        method: elaborate_bird_reload(raw)?,
+        timeout: raw.timeout_s.into(),
+        debounce: raw.debounce_s.map_or(Duration::ZERO, Into::into),
+        validate_command,
+        validate_command_args,
+        retries: raw.retries.unwrap_or(2),
+        retry_backoff: raw
+            .retry_backoff_s
+            .map_or(Duration::from_secs(1), Into::into),
+        max_retry_backoff: raw
+            .max_retry_backoff_s
+            .map_or(Duration::from_secs(30), Into::into),
+        reconcile_interval: raw
+            .reconcile_interval_s
+            .map_or(Duration::from_secs(30), Into::into),
+    })
+}
+
+fn elaborate_bird_reload(raw: &raw::BirdReload) -> Result<BirdReload> {
+    match (&raw.command, &raw.socket) {
+        (Some(command), None) => {
+            if raw.configure.is_some() {
+                bail!("'bird_reload.configure' can only be used with 'bird_reload.socket'");
+            }
+            let (command, args) = command.split_first().wrap_err("'bird_reload.command' should contain at least one element: the path to the executable to run")?;
+            Ok(BirdReload::Command {
+                command: command.to_owned(),
+                args: args.to_owned(),
+            })
+        }
+        (None, Some(socket)) => Ok(BirdReload::Socket {
+            path: socket.to_owned(),
+            configure: raw.configure.unwrap_or(ConfigureMode::Normal),
+        }),
+        _ => bail!("'bird_reload' should define either 'command' or 'socket'"),
+    }
+}
+
+fn elaborate_service_definition(raw: raw::ServiceDefinition) -> Result<ServiceDefinition> {
+    let check = elaborate_service_check(&raw)?;
+    let dampening = raw
+        .dampening
+        .as_ref()
+        .map(|d| elaborate_dampening(d, &raw.service_name))
+        .transpose()?;
+    let int_function = raw
+        .int_function
+        .as_ref()
+        .map(|f| elaborate_int_function(f, &raw.service_name))
+        .transpose()?;
+    let prefixes = raw.prefixes.unwrap_or_default();
+    if let Some(prefix) = prefixes.iter().find(|p| parse_prefix(p).is_none()) {
+        bail!(
+            "'prefixes' of service '{}' contains '{prefix}', which is not a prefix like 192.0.2.1/32",
+            raw.service_name
+        );
+    }
+    Ok(ServiceDefinition {
+        service_name: raw.service_name,
+        function_name: raw.function_name,
+        check,
+        interval: raw.interval_s.into(),
+        command_timeout: raw.command_timeout_s.into(),
+        fall: raw.fall,
+        rise: raw.rise,
+        min_up_time: raw.min_up_time_s.map_or(Duration::ZERO, Into::into),
+        min_down_time: raw.min_down_time_s.map_or(Duration::ZERO, Into::into),
+        initial_state: raw.initial_state.unwrap_or(InitialState::RiseOnce),
+        startup_grace: raw.startup_grace_s.map_or(Duration::ZERO, Into::into),
+        on_warning: raw.on_warning.unwrap_or(OnWarning::Failure),
+        dampening,
+        depends_on: raw.depends_on.unwrap_or_default(),
+        tags: raw.tags.unwrap_or_default(),
+        prefixes,
+        template: raw.template,
+        variables: raw.variables.unwrap_or_default(),
+        int_function,
+    })
+}
+
+fn elaborate_int_function(raw: &raw::IntFunction, service_name: &str) -> Result<IntFunction> {
+    let best_latency: Option<Duration> = raw.best_latency_s.map(Into::into);
+    let worst_latency: Option<Duration> = raw.worst_latency_s.map(Into::into);
+    let source = match (
+        raw.source.unwrap_or(raw::IntSource::Health),
+        best_latency,
+        worst_latency,
+    ) {
+        (raw::IntSource::Health, None, None) => IntSource::Health,
+        (raw::IntSource::Health, _, _) => bail!("'int_function.best_latency_s' and 'int_function.worst_latency_s' of service '{service_name}' can only be used with 'source = \"latency\"'"),
+        (raw::IntSource::Latency, Some(best_latency), Some(worst_latency))
+            if best_latency < worst_latency =>
+        {
+            IntSource::Latency {
+                best_latency,
+                worst_latency,
+            }
+        }
+        (raw::IntSource::Latency, _, _) => bail!("'int_function' of service '{service_name}' should verify best_latency_s < worst_latency_s with 'source = \"latency\"'"),
+    };
+    // Each change of the value reloads BIRD, which should not happen on every check because of the latency jitter
+    let default_step = match source {
+        IntSource::Health => 1,
+        IntSource::Latency { .. } => (raw.best.abs_diff(raw.worst) / 10).max(1),
+    };
+    let step = raw.step.unwrap_or(default_step);
+    if step == 0 {
+        bail!("'int_function.step' of service '{service_name}' should be positive");
+    }
+    Ok(IntFunction {
+        function_name: raw.function_name.clone(),
+        source,
+        best: raw.best,
+        worst: raw.worst,
+        step,
+    })
+}
+
+fn elaborate_dampening(raw: &raw::Dampening, service_name: &str) -> Result<Dampening> {
+    let dampening = Dampening {
+        penalty: raw.penalty.unwrap_or(1000.0),
+        half_life: raw.half_life_s.map_or(Duration::from_mins(15), Into::into),
+        suppress: raw.suppress.unwrap_or(2000.0),
+        reuse: raw.reuse.unwrap_or(750.0),
+        max_penalty: raw.max_penalty.unwrap_or(12000.0),
+    };
+    if dampening.half_life.is_zero() {
+        bail!("'service_definitions.dampening.half_life_s' of service '{service_name}' should be positive");
+    }
+    if !(0.0 < dampening.reuse
+        && dampening.reuse < dampening.suppress
+        && dampening.suppress < dampening.max_penalty)
+    {
+        bail!("'service_definitions.dampening' of service '{service_name}' should verify 0 < reuse < suppress < max_penalty");
+    }
+    Ok(dampening)
+}
+
+/// Check that each dependency refers to exactly one service, and that there is no dependency cycle
+fn check_dependencies(service_definitions: &[ServiceDefinition]) -> Result<()> {
+    /// Depth-first search. `path` contains the services being visited, to report the cycle
+    fn visit(
+        service_definitions: &[ServiceDefinition],
+        id: usize,
+        path: &mut Vec<usize>,
+        visited: &mut [bool],
+    ) -> Result<()> {
+        if let Some(cycle_start) = path.iter().position(|&p| p == id) {
+            let cycle = path[cycle_start..]
+                .iter()
+                .chain([&id])
+                .map(|&i| format!("'{}'", service_definitions[i].service_name))
+                .join(" -> ");
+            bail!("Dependency cycle between services: {cycle}");
+        }
+        if visited[id] {
+            return Ok(());
+        }
+        path.push(id);
+        for dependency in &service_definitions[id].depends_on {
+            let dependency_id = service_id(service_definitions, dependency)
+                .expect("dependencies are checked before looking for cycles");
+            visit(service_definitions, dependency_id, path, visited)?;
+        }
+        path.pop();
+        visited[id] = true;
+        Ok(())
+    }
+
+    for def in service_definitions {
+        for dependency in &def.depends_on {
+            match service_definitions
+                .iter()
+                .filter(|d| d.service_name == *dependency)
+                .count()
+            {
+                1 => {}
+                0 => bail!(
+                    "'service_definitions.depends_on' of service '{}' refers to unknown service '{dependency}'",
+                    def.service_name
+                ),
+                _ => bail!(
+                    "'service_definitions.depends_on' of service '{}' refers to '{dependency}', but several services have this name",
+                    def.service_name
+                ),
+            }
+        }
+    }
+
+    let mut visited = vec![false; service_definitions.len()];
+    for id in 0..service_definitions.len() {
+        visit(service_definitions, id, &mut Vec::new(), &mut visited)?;
+    }
+    Ok(())
+}
+
+/// Where a check is defined, to write helpful error messages
+struct CheckLocation {
+    /// e.g. `service_definitions.checks`
+    table: &'static str,
+    /// e.g. `check 'disk' of service 'frontend'`
+    description: String,
+}
+
+fn elaborate_service_check(raw: &raw::ServiceDefinition) -> Result<Check> {
+    use raw::CheckType;
+
+    let service_name = &raw.service_name;
+    let location = CheckLocation {
+        table: "service_definitions",
+        description: format!("service '{service_name}'"),
+    };
+
+    if raw.check_type != Some(CheckType::Composite) {
+        let composite_fields = [
+            ("checks", raw.checks.is_some()),
+            ("combine", raw.combine.is_some()),
+            ("at_least", raw.at_least.is_some()),
+        ];
+        for (field, is_set) in composite_fields {
+            if is_set {
+                bail!("'service_definitions.{field}' of service '{service_name}' is only allowed for a check of type 'composite'");
+            }
+        }
+        return elaborate_check(&raw.check_definition(), &location);
+    }
+
+    // The service only groups its sub-checks, and should not define a check itself
+    reject_fields_of_other_check_types(&raw.check_definition(), CheckType::Composite, &location)?;
+
+    let sub_checks = raw
+        .checks
+        .as_deref()
+        .unwrap_or_default()
+        .iter()
+        .map(|sub| {
+            let location = CheckLocation {
+                table: "service_definitions.checks",
+                description: format!("check '{}' of service '{service_name}'", sub.name),
+            };
+            Ok(SubCheck {
+                name: sub.name.clone(),
+                check: elaborate_check(sub, &location)?,
+            })
         })
+        .collect::<Result<Vec<_>>>()?;
+    if sub_checks.is_empty() {
+        bail!("'service_definitions.checks' of service '{service_name}' should contain at least one check");
+    }
+    if let Some(name) = sub_checks.iter().map(|c| &c.name).duplicates().next() {
+        bail!("Service '{service_name}' has several checks named '{name}'");
+    }
+
+    let combine = match (raw.combine, raw.at_least) {
+        (None | Some(raw::Combine::All), None) => Combine::All,
+        (Some(raw::Combine::Any), None) => Combine::Any,
+        (None, Some(n)) if n >= 1 && n as usize <= sub_checks.len() => Combine::AtLeast(n),
+        (None, Some(n)) => bail!(
+            "'service_definitions.at_least' of service '{service_name}' is {n}, it should be between 1 and its number of checks ({})",
+            sub_checks.len()
+        ),
+        (Some(_), Some(_)) => bail!(
+            "'service_definitions.combine' and 'service_definitions.at_least' of service '{service_name}' cannot be used together"
+        ),
+    };
+
+    Ok(Check::Composite(CompositeCheck {
+        checks: sub_checks,
+        combine,
+    }))
+}
+
+fn elaborate_check(raw: &raw::CheckDefinition, location: &CheckLocation) -> Result<Check> {
+    use raw::CheckType;
+
+    let check_type = raw.check_type.unwrap_or(CheckType::Command);
+    if check_type == CheckType::Composite {
+        bail!(
+            "{} is of type 'composite', which is only allowed for a service",
+            location.description
+        );
     }
+    reject_fields_of_other_check_types(raw, check_type, location)?;
+
+    let check = match check_type {
+        CheckType::Command => Check::Command(elaborate_command_check(raw, location)?),
+        CheckType::Http => Check::Http(elaborate_http_check(raw, location)?),
+        CheckType::Tcp => Check::Tcp(TcpCheck {
+            address: raw.address.clone().context(format!(
+                "'{}.address' of {} is required for a check of type 'tcp'",
+                location.table, location.description
+            ))?,
+            send: raw.send.clone(),
+            response_regex: raw.response_regex.clone(),
+        }),
+        CheckType::Dns => Check::Dns(elaborate_dns_check(raw, location)?),
+        CheckType::Composite => unreachable!("rejected above"),
+    };
+    Ok(check)
+}
+
+fn reject_fields_of_other_check_types(
+    raw: &raw::CheckDefinition,
+    check_type: raw::CheckType,
+    location: &CheckLocation,
+) -> Result<()> {
+    use raw::CheckType;
+
+    // Fields which only make sense for one type of check
+    let specific_fields: [(CheckType, &[(&str, bool)]); 4] = [
+        (
+            CheckType::Command,
+            &[
+                ("command", raw.command.is_some()),
+                ("result_mode", raw.result_mode.is_some()),
+                ("success_exit_codes", raw.success_exit_codes.is_some()),
+                ("stdout_regex", raw.stdout_regex.is_some()),
+                ("stdout_not_regex", raw.stdout_not_regex.is_some()),
+                ("stderr_regex", raw.stderr_regex.is_some()),
+                ("stderr_not_regex", raw.stderr_not_regex.is_some()),
+            ],
+        ),
+        (
+            CheckType::Http,
+            &[
+                ("url", raw.url.is_some()),
+                ("method", raw.method.is_some()),
+                ("expected_status", raw.expected_status.is_some()),
+                ("body_regex", raw.body_regex.is_some()),
+                ("headers", raw.headers.is_some()),
+                ("tls_verify", raw.tls_verify.is_some()),
+                ("tls_ca_file", raw.tls_ca_file.is_some()),
+            ],
+        ),
+        (
+            CheckType::Tcp,
+            &[
+                ("address", raw.address.is_some()),
+                ("send", raw.send.is_some()),
+                ("response_regex", raw.response_regex.is_some()),
+            ],
+        ),
+        (
+            CheckType::Dns,
+            &[
+                ("server", raw.server.is_some()),
+                ("query_name", raw.query_name.is_some()),
+                ("query_type", raw.query_type.is_some()),
+                ("protocol", raw.protocol.is_some()),
+                ("expected_rcode", raw.expected_rcode.is_some()),
+                ("expected_answer", raw.expected_answer.is_some()),
+            ],
+        ),
+    ];
+    for (field_check_type, fields) in specific_fields {
+        for (field, is_set) in fields {
+            if *is_set && field_check_type != check_type {
+                bail!(
+                    "'{}.{field}' of {} is only allowed for a check of type '{}', not '{}'",
+                    location.table,
+                    location.description,
+                    field_check_type.name(),
+                    check_type.name()
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn elaborate_command_check(
+    raw: &raw::CheckDefinition,
+    location: &CheckLocation,
+) -> Result<CommandCheck> {
+    let result_mode = raw.result_mode.unwrap_or(ResultMode::ExitCode);
+    if result_mode == ResultMode::Nagios && raw.success_exit_codes.is_some() {
+        bail!(
+            "'{}.success_exit_codes' of {} cannot be used with `result_mode = \"nagios\"`",
+            location.table,
+            location.description
+        );
+    }
+    let (cmd, args) = raw
+        .command
+        .as_deref()
+        .unwrap_or_default()
+        .split_first()
+        .context(format!(
+        "'{}.command' of {} should contain at least one element: the path to the executable to run",
+        location.table, location.description
+    ))?;
+    Ok(CommandCheck {
+        command: cmd.to_owned(),
+        args: args.to_owned(),
+        result_mode,
+        success_exit_codes: raw.success_exit_codes.clone().unwrap_or_else(|| vec![0]),
+        stdout: OutputMatch {
+            must_match: raw.stdout_regex.clone(),
+            must_not_match: raw.stdout_not_regex.clone(),
+        },
+        stderr: OutputMatch {
+            must_match: raw.stderr_regex.clone(),
+            must_not_match: raw.stderr_not_regex.clone(),
+        },
+    })
+}
+
+fn elaborate_http_check(raw: &raw::CheckDefinition, location: &CheckLocation) -> Result<HttpCheck> {
+    let CheckLocation { table, description } = location;
+    let mut http = HttpCheck {
+        url: raw.url.clone().context(format!(
+            "'{table}.url' of {description} is required for a check of type 'http'"
+        ))?,
+        method: raw.method.clone().unwrap_or_else(|| "GET".to_owned()),
+        expected_status: match &raw.expected_status {
+            Some(ranges) => ranges
+                .iter()
+                .map(|r| r.parse())
+                .collect::<Result<Vec<_>>>()
+                .wrap_err(format!(
+                    "Invalid '{table}.expected_status' of {description}"
+                ))?,
+            None => vec!["200-299".parse()?],
+        },
+        body_regex: raw.body_regex.clone(),
+        headers: raw.headers.clone().unwrap_or_default(),
+        tls_verify: raw.tls_verify.unwrap_or(true),
+        tls_ca_file: raw.tls_ca_file.as_ref().map(Into::into),
+        client: HttpClient::default(),
+    };
+    http.validate()
+        .wrap_err(format!("Invalid http check of {description}"))?;
+    Ok(http)
+}
+
+fn elaborate_dns_check(raw: &raw::CheckDefinition, location: &CheckLocation) -> Result<DnsCheck> {
+    let CheckLocation { table, description } = location;
+    let server = raw.server.as_deref().context(format!(
+        "'{table}.server' of {description} is required for a check of type 'dns'"
+    ))?;
+    let dns = DnsCheck {
+        server: server
+            .parse::<SocketAddr>()
+            .or_else(|_| server.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+            .wrap_err(format!(
+                "Invalid '{table}.server' of {description}: expected `ip` or `ip:port`"
+            ))?,
+        query_name: raw.query_name.clone().context(format!(
+            "'{table}.query_name' of {description} is required for a check of type 'dns'"
+        ))?,
+        query_type: raw.query_type.clone().unwrap_or_else(|| "A".to_owned()),
+        protocol: raw.protocol.unwrap_or(DnsProtocol::Udp),
+        expected_rcode: parse_rcode(raw.expected_rcode.as_deref().unwrap_or("NOERROR"))
+            .wrap_err(format!("Invalid '{table}.expected_rcode' of {description}"))?,
+        expected_answer: raw.expected_answer.clone(),
+    };
+    dns.validate()
+        .wrap_err(format!("Invalid dns check of {description}"))?;
+    Ok(dns)
 }
 
 #[cfg(test)]
 mod test {
-    use std::time::Duration;
+    use std::{collections::BTreeMap, time::Duration};
 
-    use crate::{config::GeneratedFile, service::ServiceDefinition};
+    use crate::{
+        bird_control::ConfigureMode,
+        check::{
+            command::{CommandCheck, OutputMatch, ResultMode},
+            composite::{Combine, CompositeCheck, SubCheck},
+            dns::{DnsCheck, DnsProtocol},
+            http::{HttpCheck, HttpClient},
+            tcp::TcpCheck,
+            Check,
+        },
+        config::{
+            BirdQuery, BirdReload, GeneratedContent, GeneratedFile, Shutdown, ShutdownAction,
+            StateFile,
+        },
+        deser::regex_serde::RegexSerde,
+        service::{Dampening, InitialState, IntFunction, IntSource, OnWarning, ServiceDefinition},
+    };
 
     use super::Config;
     use indoc::indoc;
@@ -155,16 +1394,48 @@ rise = 5
 "#,
         )
         .unwrap();
+        let [output] = config.outputs.as_slice() else {
+            panic!("a single output");
+        };
         assert_eq!(
-            config.generated_file,
+            output.generated_file,
             GeneratedFile {
                 path: "birdwatcher_generated.conf".to_owned(),
-                function_return_type: true
+                function_return_type: true,
+                mode: None,
+                uid: None,
+                gid: None,
+                template: None,
+                variables: BTreeMap::new(),
+                content: GeneratedContent::Functions,
+                route: "blackhole".to_owned(),
+            }
+        );
+        assert_eq!(
+            output.reload.method,
+            BirdReload::Command {
+                command: "birdc".to_owned(),
+                args: vec!["configure".to_owned()]
+            }
+        );
+        assert_eq!(output.reload.timeout, Duration::from_secs(1));
+        assert_eq!(output.reload.debounce, Duration::ZERO);
+        assert_eq!(output.reload.validate_command, None);
+        assert_eq!(output.reload.retries, 2);
+        assert_eq!(output.reload.retry_backoff, Duration::from_secs(1));
+        assert_eq!(output.reload.max_retry_backoff, Duration::from_secs(30));
+        assert_eq!(output.reload.reconcile_interval, Duration::from_secs(30));
+        assert_eq!(output.verify, None);
+        assert_eq!((&output.services, &output.tags), (&None, &None));
+        assert_eq!(config.state_file, None);
+        assert_eq!(config.drain_file, None);
+        assert_eq!(
+            config.shutdown,
+            Shutdown {
+                action: ShutdownAction::Keep,
+                drain_delay: Duration::ZERO
             }
         );
-        assert_eq!(config.reload_command, "birdc");
-        assert_eq!(config.reload_command_args, ["configure"]);
-        assert_eq!(config.reload_timeout, Duration::from_secs(1));
 
         assert_eq!(config.service_definitions.len(), 1);
         assert_eq!(
@@ -172,12 +1443,30 @@ rise = 5
             vec![ServiceDefinition {
                 service_name: "first_service".to_owned(),
                 function_name: "match_true".to_owned(),
-                command: "/bin/ls".to_owned(),
-                args: vec!["myfile.txt".to_owned()],
+                check: Check::Command(CommandCheck {
+                    command: "/bin/ls".to_owned(),
+                    args: vec!["myfile.txt".to_owned()],
+                    result_mode: ResultMode::ExitCode,
+                    success_exit_codes: vec![0],
+                    stdout: OutputMatch::default(),
+                    stderr: OutputMatch::default(),
+                }),
                 command_timeout: Duration::from_secs(2),
                 interval: Duration::from_secs(3),
                 fall: 4,
                 rise: 5,
+                min_up_time: Duration::ZERO,
+                min_down_time: Duration::ZERO,
+                initial_state: InitialState::RiseOnce,
+                startup_grace: Duration::ZERO,
+                on_warning: OnWarning::Failure,
+                dampening: None,
+                depends_on: vec![],
+                tags: vec![],
+                prefixes: vec![],
+                template: None,
+                variables: BTreeMap::new(),
+                int_function: None,
             },]
         );
     }
@@ -232,7 +1521,7 @@ raise = 4
                |
             17 | raise = 4
                | ^^^^^
-            unknown field `raise`, expected one of `service_name`, `function_name`, `command`, `interval_s`, `command_timeout_s`, `fall`, `rise`
+            unknown field `raise`, expected one of `service_name`, `function_name`, `type`, `interval_s`, `command_timeout_s`, `fall`, `rise`, `min_up_time_s`, `min_down_time_s`, `initial_state`, `startup_grace_s`, `on_warning`, `depends_on`, `tags`, `prefixes`, `template`, `variables`, `int_function`, `dampening`, `checks`, `combine`, `at_least`, `command`, `result_mode`, `success_exit_codes`, `stdout_regex`, `stdout_not_regex`, `stderr_regex`, `stderr_not_regex`, `url`, `method`, `expected_status`, `body_regex`, `headers`, `tls_verify`, `tls_ca_file`, `address`, `send`, `response_regex`, `server`, `query_name`, `query_type`, `protocol`, `expected_rcode`, `expected_answer`
             " }
         );
     }
@@ -271,4 +1560,1031 @@ fall = 1
              " }
         );
     }
+
+    #[test]
+    fn http_service() {
+        let config = Config::from_string(
+            r#"
+[generated_file]
+path = "birdwatcher_generated.conf"
+
+[bird_reload]
+command = ["birdc", "configure"]
+timeout_s = 1
+
+[[service_definitions]]
+service_name = "webserver"
+function_name = "webserver_is_up"
+type = "http"
+url = "https://localhost:8443/health"
+expected_status = ["200-299", "301"]
+headers = { Host = "example.com" }
+tls_verify = false
+command_timeout_s = 2
+interval_s = 3
+fall = 4
+rise = 5
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.service_definitions[0].check,
+            Check::Http(HttpCheck {
+                url: "https://localhost:8443/health".to_owned(),
+                method: "GET".to_owned(),
+                expected_status: vec!["200-299".parse().unwrap(), "301".parse().unwrap()],
+                body_regex: None,
+                headers: [("Host".to_owned(), "example.com".to_owned())].into(),
+                tls_verify: false,
+                tls_ca_file: None,
+                client: HttpClient::default(),
+            })
+        );
+    }
+
+    #[test]
+    fn tcp_service() {
+        let config = Config::from_string(
+            r#"
+[generated_file]
+path = "birdwatcher_generated.conf"
+
+[bird_reload]
+command = ["birdc", "configure"]
+timeout_s = 1
+
+[[service_definitions]]
+service_name = "smtp"
+function_name = "smtp_is_up"
+type = "tcp"
+address = "localhost:25"
+send = "QUIT\r\n"
+response_regex = "^221 "
+command_timeout_s = 2
+interval_s = 3
+fall = 4
+rise = 5
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.service_definitions[0].check,
+            Check::Tcp(TcpCheck {
+                address: "localhost:25".to_owned(),
+                send: Some("QUIT\r\n".to_owned()),
+                response_regex: Some(RegexSerde::new("^221 ").unwrap()),
+            })
+        );
+    }
+
+    #[test]
+    fn dns_service_default_port() {
+        let config = Config::from_string(
+            r#"
+[generated_file]
+path = "birdwatcher_generated.conf"
+
+[bird_reload]
+command = ["birdc", "configure"]
+timeout_s = 1
+
+[[service_definitions]]
+service_name = "resolver"
+function_name = "resolver_is_up"
+type = "dns"
+server = "192.0.2.53"
+query_name = "example.com."
+command_timeout_s = 2
+interval_s = 3
+fall = 4
+rise = 5
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.service_definitions[0].check,
+            Check::Dns(DnsCheck {
+                server: "192.0.2.53:53".parse().unwrap(),
+                query_name: "example.com.".to_owned(),
+                query_type: "A".to_owned(),
+                protocol: DnsProtocol::Udp,
+                expected_rcode: 0,
+                expected_answer: None,
+            })
+        );
+    }
+
+    #[test]
+    fn field_of_another_check_type_should_fail() {
+        let config = Config::from_string(
+            r#"
+[generated_file]
+path = "birdwatcher_generated.conf"
+
+[bird_reload]
+command = ["birdc", "configure"]
+timeout_s = 2
+
+[[service_definitions]]
+service_name = "first_service"
+function_name = "match_true"
+command = ["/bin/ls", "1"]
+url = "http://localhost/"
+command_timeout_s = 1
+interval_s = 1.2
+fall = 1
+rise = 3
+"#,
+        );
+        assert_eq!(
+            config.err().unwrap().to_string(),
+            "'service_definitions.url' of service 'first_service' is only allowed for a check of type 'http', not 'command'"
+        );
+    }
+
+    const COMPOSITE_SERVICE: &str = r#"
+[generated_file]
+path = "birdwatcher_generated.conf"
+
+[bird_reload]
+command = ["birdc", "configure"]
+timeout_s = 1
+
+[[service_definitions]]
+service_name = "frontend"
+function_name = "frontend_is_up"
+type = "composite"
+at_least = 2
+command_timeout_s = 2
+interval_s = 3
+fall = 4
+rise = 5
+
+[[service_definitions.checks]]
+name = "http"
+type = "http"
+url = "http://localhost/health"
+
+[[service_definitions.checks]]
+name = "smtp"
+type = "tcp"
+address = "localhost:25"
+
+[[service_definitions.checks]]
+name = "disk"
+command = ["/usr/lib/nagios/plugins/check_disk", "-w", "20%"]
+result_mode = "nagios"
+"#;
+
+    #[test]
+    fn composite_service() {
+        let config = Config::from_string(COMPOSITE_SERVICE).unwrap();
+        let Check::Composite(CompositeCheck { checks, combine }) =
+            &config.service_definitions[0].check
+        else {
+            panic!("Expected a composite check");
+        };
+        assert_eq!(*combine, Combine::AtLeast(2));
+        assert_eq!(
+            checks.iter().map(|c| c.name.as_str()).collect::<Vec<_>>(),
+            ["http", "smtp", "disk"]
+        );
+        assert_eq!(
+            checks[1],
+            SubCheck {
+                name: "smtp".to_owned(),
+                check: Check::Tcp(TcpCheck {
+                    address: "localhost:25".to_owned(),
+                    send: None,
+                    response_regex: None,
+                }),
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_composite_service_should_fail() {
+        let error = |from: &str, to: &str| {
+            Config::from_string(&COMPOSITE_SERVICE.replacen(from, to, 1))
+                .err()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(
+            error("at_least = 2", "at_least = 4"),
+            "'service_definitions.at_least' of service 'frontend' is 4, it should be between 1 and its number of checks (3)"
+        );
+        assert_eq!(
+            error("at_least = 2", "at_least = 2\ncombine = \"any\""),
+            "'service_definitions.combine' and 'service_definitions.at_least' of service 'frontend' cannot be used together"
+        );
+        assert_eq!(
+            error("name = \"smtp\"", "name = \"http\""),
+            "Service 'frontend' has several checks named 'http'"
+        );
+        assert_eq!(
+            error("address = \"localhost:25\"", "url = \"http://localhost:25/\""),
+            "'service_definitions.checks.url' of check 'smtp' of service 'frontend' is only allowed for a check of type 'http', not 'tcp'"
+        );
+        assert_eq!(
+            error("type = \"composite\"", "type = \"http\""),
+            "'service_definitions.checks' of service 'frontend' is only allowed for a check of type 'composite'"
+        );
+        assert_eq!(
+            error("type = \"tcp\"", "type = \"composite\""),
+            "check 'smtp' of service 'frontend' is of type 'composite', which is only allowed for a service"
+        );
+    }
+
+    #[test]
+    fn dependencies() {
+        let config = |frontend_depends_on: &str, backend_depends_on: &str| {
+            Config::from_string(&format!(
+                r#"
+[generated_file]
+path = "birdwatcher_generated.conf"
+
+[bird_reload]
+command = ["birdc", "configure"]
+timeout_s = 1
+
+[[service_definitions]]
+service_name = "frontend"
+function_name = "frontend_is_up"
+command = ["/bin/true"]
+depends_on = {frontend_depends_on}
+command_timeout_s = 1
+interval_s = 1
+fall = 1
+rise = 1
+
+[[service_definitions]]
+service_name = "backend"
+function_name = "backend_is_up"
+command = ["/bin/true"]
+depends_on = {backend_depends_on}
+command_timeout_s = 1
+interval_s = 1
+fall = 1
+rise = 1
+"#
+            ))
+        };
+
+        let c = config(r#"["backend"]"#, "[]").unwrap();
+        assert_eq!(c.service_definitions[0].depends_on, ["backend"]);
+
+        assert_eq!(
+            config(r#"["database"]"#, "[]").err().unwrap().to_string(),
+            "'service_definitions.depends_on' of service 'frontend' refers to unknown service 'database'"
+        );
+        assert_eq!(
+            config(r#"["backend"]"#, r#"["frontend"]"#)
+                .err()
+                .unwrap()
+                .to_string(),
+            "Dependency cycle between services: 'frontend' -> 'backend' -> 'frontend'"
+        );
+        assert_eq!(
+            config(r#"["frontend"]"#, "[]").err().unwrap().to_string(),
+            "Dependency cycle between services: 'frontend' -> 'frontend'"
+        );
+    }
+
+    #[test]
+    fn duplicate_function_name_should_fail() {
+        let config = |frontend: &str, backend: &str| {
+            Config::from_string(&format!(
+                r#"
+[generated_file]
+path = "birdwatcher_generated.conf"
+
+[bird_reload]
+command = ["birdc", "configure"]
+timeout_s = 1
+
+[[service_definitions]]
+service_name = "frontend"
+{frontend}
+command = ["/bin/true"]
+command_timeout_s = 1
+interval_s = 1
+fall = 1
+rise = 1
+
+[[service_definitions]]
+service_name = "backend"
+{backend}
+command = ["/bin/true"]
+command_timeout_s = 1
+interval_s = 1
+fall = 1
+rise = 1
+"#
+            ))
+        };
+        assert_eq!(
+            config(r#"function_name = "is_up""#, r#"function_name = "is_up""#)
+                .err()
+                .unwrap()
+                .to_string(),
+            "Several services define the BIRD function 'is_up'"
+        );
+        // The `_degraded` function is generated too
+        assert_eq!(
+            config(
+                "function_name = \"is_up\"\non_warning = \"degraded\"",
+                r#"function_name = "is_up_degraded""#
+            )
+            .err()
+            .unwrap()
+            .to_string(),
+            "Several services define the BIRD function 'is_up_degraded'"
+        );
+        assert!(config(
+            r#"function_name = "is_up""#,
+            r#"function_name = "is_up_degraded""#
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn bird_reload_socket() {
+        let config = |bird_reload: &str| {
+            Config::from_string(&format!(
+                r#"
+[generated_file]
+path = "birdwatcher_generated.conf"
+
+[bird_reload]
+timeout_s = 1
+{bird_reload}
+
+[[service_definitions]]
+service_name = "frontend"
+function_name = "is_up"
+command = ["/bin/true"]
+command_timeout_s = 1
+interval_s = 1
+fall = 1
+rise = 1
+"#
+            ))
+        };
+
+        assert_eq!(
+            config(
+                r#"socket = "/run/bird/bird.ctl"
+configure = "soft""#
+            )
+            .unwrap()
+            .outputs[0]
+                .reload
+                .method,
+            BirdReload::Socket {
+                path: "/run/bird/bird.ctl".to_owned(),
+                configure: ConfigureMode::Soft
+            }
+        );
+        assert_eq!(
+            config(
+                r#"socket = "/run/bird/bird.ctl"
+command = ["birdc", "configure"]"#
+            )
+            .err()
+            .unwrap()
+            .to_string(),
+            "'bird_reload' should define either 'command' or 'socket'"
+        );
+        assert_eq!(
+            config(
+                r#"command = ["birdc", "configure"]
+configure = "check""#
+            )
+            .err()
+            .unwrap()
+            .to_string(),
+            "'bird_reload.configure' can only be used with 'bird_reload.socket'"
+        );
+    }
+
+    #[test]
+    fn template() {
+        let config = |template: &str| {
+            Config::from_string(&format!(
+                r#"
+[generated_file]
+path = "birdwatcher_generated.conf"
+template = "define {{{{ function_name }}}}_up = {{{{ value }}}};"
+
+[bird_reload]
+command = ["birdc", "configure"]
+timeout_s = 1
+
+[[service_definitions]]
+service_name = "frontend"
+function_name = "is_up"
+command = ["/bin/true"]
+command_timeout_s = 1
+interval_s = 1
+fall = 1
+rise = 1
+{template}
+"#
+            ))
+        };
+
+        config("").unwrap();
+        config(
+            r#"template = "define {{ function_name }}_community = {{ community }};"
+variables = { community = "(65000, 1)" }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            format!(
+                "{:#}",
+                config(r#"template = "{{ community }}""#).err().unwrap()
+            ),
+            "Invalid template for service 'frontend': Unknown variable 'community'"
+        );
+        assert_eq!(
+            config(r#"variables = { value = "true" }"#)
+                .err()
+                .unwrap()
+                .to_string(),
+            "'variables' cannot redefine the built-in variable 'value'"
+        );
+    }
+
+    #[test]
+    fn validate_command() {
+        let config = |validate_command: &str| {
+            Config::from_string(&format!(
+                r#"
+[generated_file]
+path = "birdwatcher_generated.conf"
+
+[bird_reload]
+command = ["birdc", "configure"]
+timeout_s = 1
+validate_command = {validate_command}
+
+[[service_definitions]]
+service_name = "frontend"
+function_name = "is_up"
+command = ["/bin/true"]
+command_timeout_s = 1
+interval_s = 1
+fall = 1
+rise = 1
+"#
+            ))
+        };
+
+        let validated = config(r#"["bird", "-p", "-c", "/etc/bird/bird.conf"]"#).unwrap();
+        let reload = &validated.outputs[0].reload;
+        assert_eq!(reload.validate_command.as_deref(), Some("bird"));
+        assert_eq!(
+            reload.validate_command_args,
+            ["-p", "-c", "/etc/bird/bird.conf"]
+        );
+
+        assert_eq!(
+            config("[]").err().unwrap().to_string(),
+            "'bird_reload.validate_command' should contain at least one element: the path to the executable to run"
+        );
+    }
+
+    #[test]
+    fn dampening() {
+        let config = Config::from_string(
+            r#"
+[generated_file]
+path = "birdwatcher_generated.conf"
+
+[bird_reload]
+command = ["birdc", "configure"]
+timeout_s = 1
+
+[[service_definitions]]
+service_name = "flappy"
+function_name = "flappy_is_up"
+command = ["/bin/true"]
+command_timeout_s = 1
+interval_s = 1
+fall = 1
+rise = 1
+
+[service_definitions.dampening]
+half_life_s = 60
+reuse = 500
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.service_definitions[0].dampening,
+            Some(Dampening {
+                penalty: 1000.0,
+                half_life: Duration::from_mins(1),
+                suppress: 2000.0,
+                reuse: 500.0,
+                max_penalty: 12000.0,
+            })
+        );
+    }
+
+    #[test]
+    fn state_file() {
+        let config = Config::from_string(
+            r#"
+[generated_file]
+path = "birdwatcher_generated.conf"
+
+[bird_reload]
+command = ["birdc", "configure"]
+timeout_s = 1
+
+[state_file]
+path = "/var/lib/birdwatcher/state.toml"
+
+[[service_definitions]]
+service_name = "first_service"
+function_name = "match_true"
+command = ["/bin/true"]
+command_timeout_s = 1
+interval_s = 1
+fall = 1
+rise = 1
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.state_file,
+            Some(StateFile {
+                path: "/var/lib/birdwatcher/state.toml".to_owned(),
+                max_age: Duration::from_mins(5),
+            })
+        );
+    }
+
+    #[test]
+    fn drain_file() {
+        let config = |drain_tags: &str| {
+            Config::from_string(&format!(
+                r#"
+[generated_file]
+path = "birdwatcher_generated.conf"
+
+[bird_reload]
+command = ["birdc", "configure"]
+timeout_s = 1
+
+[drain_file]
+path = "/etc/birdwatcher/drain"
+{drain_tags}
+
+[[service_definitions]]
+service_name = "anycast"
+function_name = "anycast_is_up"
+command = ["/bin/true"]
+tags = ["anycast"]
+command_timeout_s = 1
+interval_s = 1
+fall = 1
+rise = 1
+
+[[service_definitions]]
+service_name = "unicast"
+function_name = "unicast_is_up"
+command = ["/bin/true"]
+command_timeout_s = 1
+interval_s = 1
+fall = 1
+rise = 1
+"#
+            ))
+        };
+
+        let c = config("").unwrap();
+        let drain_file = c.drain_file.as_ref().unwrap();
+        assert_eq!(drain_file.path, "/etc/birdwatcher/drain");
+        assert!(c
+            .service_definitions
+            .iter()
+            .all(|def| drain_file.drains(def)));
+
+        let c = config(r#"tags = ["anycast"]"#).unwrap();
+        let drain_file = c.drain_file.as_ref().unwrap();
+        assert!(drain_file.drains(&c.service_definitions[0]));
+        assert!(!drain_file.drains(&c.service_definitions[1]));
+
+        assert_eq!(
+            config(r#"tags = ["multicast"]"#).err().unwrap().to_string(),
+            "'drain_file.tags' contains 'multicast', which is not a tag of any service"
+        );
+    }
+
+    #[test]
+    fn verify() {
+        let config = |bird_reload: &str, verify: &str| {
+            Config::from_string(&format!(
+                r#"
+[generated_file]
+path = "birdwatcher_generated.conf"
+
+[bird_reload]
+{bird_reload}
+timeout_s = 1
+
+[verify]
+protocol = "bgp_uplink"
+{verify}
+
+[[service_definitions]]
+service_name = "resolver"
+function_name = "resolver_is_up"
+command = ["/bin/true"]
+command_timeout_s = 1
+interval_s = 1
+fall = 1
+rise = 1
+"#
+            ))
+        };
+        let query = |bird_reload: &str, verify: &str| {
+            config(bird_reload, verify).map(|c| c.outputs[0].verify.clone().unwrap().query)
+        };
+
+        // BIRD is queried the way it is reloaded by default
+        assert_eq!(
+            query(r#"socket = "/run/bird/bird6.ctl""#, "").unwrap(),
+            BirdQuery::Socket("/run/bird/bird6.ctl".to_owned())
+        );
+        assert_eq!(
+            query(
+                r#"command = ["birdc", "-s", "/run/bird/bird6.ctl", "configure"]"#,
+                ""
+            )
+            .unwrap(),
+            BirdQuery::Command {
+                command: "birdc".to_owned(),
+                args: vec!["-s".to_owned(), "/run/bird/bird6.ctl".to_owned()]
+            }
+        );
+        assert_eq!(
+            query(
+                r#"command = ["birdc6", "configure"]"#,
+                r#"socket = "/run/bird/bird.ctl""#
+            )
+            .unwrap(),
+            BirdQuery::Socket("/run/bird/bird.ctl".to_owned())
+        );
+        assert_eq!(
+            query(
+                r#"command = ["/usr/local/bin/reload-bird"]"#,
+                r#"command = ["birdc6"]"#
+            )
+            .unwrap(),
+            BirdQuery::Command {
+                command: "birdc6".to_owned(),
+                args: vec![]
+            }
+        );
+
+        assert_eq!(
+            config(r#"command = ["/usr/local/bin/reload-bird"]"#, "")
+                .err()
+                .unwrap()
+                .to_string(),
+            "'verify' should define 'socket' or 'command': BIRD cannot be queried with the 'bird_reload.command', which does not end with 'configure'"
+        );
+        assert_eq!(
+            config(
+                r#"command = ["birdc", "configure"]"#,
+                "socket = \"/run/bird/bird.ctl\"\ncommand = [\"birdc\"]"
+            )
+            .err()
+            .unwrap()
+            .to_string(),
+            "'verify' should define either 'socket' or 'command', not both"
+        );
+    }
+
+    #[test]
+    fn outputs() {
+        let config = |outputs: &str| {
+            Config::from_string(&format!(
+                r#"
+{outputs}
+
+[[service_definitions]]
+service_name = "resolver"
+function_name = "resolver_is_up"
+command = ["/bin/true"]
+tags = ["anycast"]
+command_timeout_s = 1
+interval_s = 1
+fall = 1
+rise = 1
+
+[[service_definitions]]
+service_name = "frontend"
+function_name = "frontend_is_up"
+command = ["/bin/true"]
+command_timeout_s = 1
+interval_s = 1
+fall = 1
+rise = 1
+"#
+            ))
+        };
+        let output = |path: &str, selection: &str| {
+            format!(
+                r#"
+[[outputs]]
+{selection}
+generated_file = {{ path = "{path}" }}
+bird_reload = {{ socket = "/run/bird/{path}.ctl", timeout_s = 1 }}
+"#
+            )
+        };
+
+        let c = config(&format!(
+            "{}{}",
+            output("bird4.conf", r#"tags = ["anycast"]"#),
+            output("bird6.conf", r#"services = ["frontend"]"#)
+        ))
+        .unwrap();
+        let [bird4, bird6] = c.outputs.as_slice() else {
+            panic!("two outputs");
+        };
+        assert_eq!(bird4.generated_file.path, "bird4.conf");
+        assert_eq!(
+            bird6.reload.method,
+            BirdReload::Socket {
+                path: "/run/bird/bird6.conf.ctl".to_owned(),
+                configure: ConfigureMode::Normal
+            }
+        );
+        assert!(bird4.includes(&c.service_definitions[0]));
+        assert!(!bird4.includes(&c.service_definitions[1]));
+        assert!(!bird6.includes(&c.service_definitions[0]));
+        assert!(bird6.includes(&c.service_definitions[1]));
+
+        let c = config(&output("bird.conf", "")).unwrap();
+        assert!(c
+            .service_definitions
+            .iter()
+            .all(|def| c.outputs[0].includes(def)));
+
+        assert_eq!(
+            config(&format!(
+                "{}{}",
+                output("bird.conf", ""),
+                output("bird.conf", "")
+            ))
+            .err()
+            .unwrap()
+            .to_string(),
+            "Several outputs write the generated file 'bird.conf'"
+        );
+        assert_eq!(
+            config(&output("bird.conf", r#"services = ["backend"]"#))
+                .err()
+                .unwrap()
+                .to_string(),
+            "'outputs.services' contains 'backend', which is not a service"
+        );
+        assert_eq!(
+            config(&output("bird.conf", r#"tags = ["multicast"]"#))
+                .err()
+                .unwrap()
+                .to_string(),
+            "'outputs.tags' contains 'multicast', which is not a tag of any service"
+        );
+        assert_eq!(
+            config(&format!(
+                "{}[bird_reload]\ncommand = [\"birdc\", \"configure\"]\ntimeout_s = 1",
+                output("bird.conf", "")
+            ))
+            .err()
+            .unwrap()
+            .to_string(),
+            "The configuration should define either 'generated_file' and 'bird_reload', or 'outputs'"
+        );
+        assert_eq!(
+            config("outputs = []").err().unwrap().to_string(),
+            "The configuration should define either 'generated_file' and 'bird_reload', or 'outputs'"
+        );
+    }
+
+    #[test]
+    fn generated_content() {
+        let config = |generated_file: &str, prefixes: &str| {
+            Config::from_string(&format!(
+                r#"
+[generated_file]
+path = "birdwatcher_generated.conf"
+{generated_file}
+
+[bird_reload]
+command = ["birdc", "configure"]
+timeout_s = 1
+
+[[service_definitions]]
+service_name = "resolver"
+function_name = "resolver_is_up"
+command = ["/bin/true"]
+prefixes = {prefixes}
+command_timeout_s = 1
+interval_s = 1
+fall = 1
+rise = 1
+"#
+            ))
+        };
+
+        let c = config(r#"content = "routes""#, r#"["192.0.2.53/32"]"#).unwrap();
+        let generated_file = &c.outputs[0].generated_file;
+        assert_eq!(generated_file.content, GeneratedContent::Routes);
+        assert_eq!(generated_file.route, "blackhole");
+        let c = config(
+            r#"content = "routes"
+route = "via 198.51.100.1""#,
+            r#"["192.0.2.53/32", "2001:db8::53/128"]"#,
+        )
+        .unwrap();
+        assert_eq!(c.outputs[0].generated_file.route, "via 198.51.100.1");
+        assert_eq!(
+            config(r#"route = "unreachable""#, "[]")
+                .err()
+                .unwrap()
+                .to_string(),
+            r#"'generated_file.route' can only be used with 'generated_file.content = "routes"'"#
+        );
+
+        config(
+            r#"content = "prefix_sets""#,
+            r#"["192.0.2.53/32", "198.51.100.53/32"]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config(
+                r#"content = "prefix_sets""#,
+                r#"["192.0.2.53/32", "2001:db8::53/128"]"#
+            )
+            .err()
+            .unwrap()
+            .to_string(),
+            "'prefixes' of service 'resolver' mixes IPv4 and IPv6, which a prefix set cannot"
+        );
+        for content in [r#"content = "routes""#, r#"content = "prefix_sets""#] {
+            assert_eq!(
+                config(content, "[]").err().unwrap().to_string(),
+                "Service 'resolver' has no 'prefixes', which the generated file 'birdwatcher_generated.conf' requires"
+            );
+        }
+    }
+
+    #[test]
+    fn prefix_set_named_like_a_function_should_fail() {
+        let config = |function_name: &str| {
+            Config::from_string(&format!(
+                r#"
+[generated_file]
+path = "birdwatcher_generated.conf"
+content = "prefix_sets"
+
+[bird_reload]
+command = ["birdc", "configure"]
+timeout_s = 1
+
+[[service_definitions]]
+service_name = "resolver"
+function_name = "resolver"
+command = ["/bin/true"]
+prefixes = ["192.0.2.53/32"]
+command_timeout_s = 1
+interval_s = 1
+fall = 1
+rise = 1
+
+[[service_definitions]]
+service_name = "web"
+function_name = "{function_name}"
+command = ["/bin/true"]
+prefixes = ["192.0.2.80/32"]
+command_timeout_s = 1
+interval_s = 1
+fall = 1
+rise = 1
+"#
+            ))
+        };
+        config("web").unwrap();
+        assert_eq!(
+            config("resolver_prefixes").err().unwrap().to_string(),
+            "The prefix set 'resolver_prefixes' of service 'resolver' has the name of a BIRD function"
+        );
+    }
+
+    #[test]
+    fn int_function() {
+        let config = |int_function: &str| {
+            Config::from_string(&format!(
+                r#"
+[generated_file]
+path = "birdwatcher_generated.conf"
+
+[bird_reload]
+command = ["birdc", "configure"]
+timeout_s = 1
+
+[[service_definitions]]
+service_name = "resolver"
+function_name = "resolver_is_up"
+command = ["/bin/true"]
+command_timeout_s = 1
+interval_s = 1
+fall = 1
+rise = 1
+int_function = {{ {int_function} }}
+"#
+            ))
+        };
+
+        let c = config(r#"function_name = "resolver_med", best = 0, worst = 200"#).unwrap();
+        assert_eq!(
+            c.service_definitions[0].int_function,
+            Some(IntFunction {
+                function_name: "resolver_med".to_owned(),
+                source: IntSource::Health,
+                best: 0,
+                worst: 200,
+                step: 1,
+            })
+        );
+        let c = config(
+            r#"function_name = "resolver_med", source = "latency", best = 0, worst = 200, step = 10, best_latency_s = 0.01, worst_latency_s = 0.5"#,
+        )
+        .unwrap();
+        assert_eq!(
+            c.service_definitions[0]
+                .int_function
+                .as_ref()
+                .unwrap()
+                .source,
+            IntSource::Latency {
+                best_latency: Duration::from_millis(10),
+                worst_latency: Duration::from_millis(500),
+            }
+        );
+        let c = config(
+            r#"function_name = "resolver_med", source = "latency", best = 0, worst = 200, best_latency_s = 0.01, worst_latency_s = 0.5"#,
+        )
+        .unwrap();
+        assert_eq!(
+            c.service_definitions[0].int_function.as_ref().unwrap().step,
+            20
+        );
+        let c = config(
+            r#"function_name = "resolver_med", source = "latency", best = 5, worst = 0, best_latency_s = 0.01, worst_latency_s = 0.5"#,
+        )
+        .unwrap();
+        assert_eq!(
+            c.service_definitions[0].int_function.as_ref().unwrap().step,
+            1
+        );
+
+        assert_eq!(
+            config(r#"function_name = "resolver_is_up", best = 0, worst = 200"#)
+                .err()
+                .unwrap()
+                .to_string(),
+            "Several services define the BIRD function 'resolver_is_up'"
+        );
+        assert_eq!(
+            config(r#"function_name = "resolver_med", best = 0, worst = 200, step = 0"#)
+                .err()
+                .unwrap()
+                .to_string(),
+            "'int_function.step' of service 'resolver' should be positive"
+        );
+        assert_eq!(
+            config(r#"function_name = "resolver_med", source = "latency", best = 0, worst = 200, best_latency_s = 0.5, worst_latency_s = 0.5"#)
+                .err()
+                .unwrap()
+                .to_string(),
+            r#"'int_function' of service 'resolver' should verify best_latency_s < worst_latency_s with 'source = "latency"'"#
+        );
+        assert_eq!(
+            config(
+                r#"function_name = "resolver_med", best = 0, worst = 200, worst_latency_s = 0.5"#
+            )
+            .err()
+            .unwrap()
+            .to_string(),
+            r#"'int_function.best_latency_s' and 'int_function.worst_latency_s' of service 'resolver' can only be used with 'source = "latency"'"#
+        );
+    }
 }
@@ -1 +1,2 @@
 pub mod duration_deser_f32;
+pub mod regex_serde;
@@ -0,0 +1,57 @@
+use std::{fmt, ops::Deref};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// `regex::Regex` does not implement `Serialize` nor `Deserialize`
+/// This wrapper (de)serialize a regex as its pattern, so it can be written in the TOML file and sent over the RPC
+#[derive(Debug, Clone)]
+pub struct RegexSerde(regex::Regex);
+
+impl RegexSerde {
+    pub fn new(pattern: &str) -> Result<RegexSerde, regex::Error> {
+        regex::Regex::new(pattern).map(RegexSerde)
+    }
+}
+
+impl<'de> Deserialize<'de> for RegexSerde {
+    fn deserialize<D>(deserializer: D) -> Result<RegexSerde, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let pattern = String::deserialize(deserializer)?;
+
+        match RegexSerde::new(&pattern) {
+            Ok(r) => Ok(r),
+            Err(e) => Err(serde::de::Error::custom(e)),
+        }
+    }
+}
+
+impl Serialize for RegexSerde {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.0.as_str())
+    }
+}
+
+impl Deref for RegexSerde {
+    type Target = regex::Regex;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl PartialEq for RegexSerde {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl fmt::Display for RegexSerde {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0.as_str())
+    }
+}
@@ -1,6 +1,12 @@
+pub mod bird;
+pub mod bird_control;
+pub mod check;
 pub mod config;
 pub mod deser;
 pub mod rpc;
 pub mod service;
+pub mod state_file;
 pub mod telemetry;
+pub mod template;
 pub mod tui;
+pub mod verify;
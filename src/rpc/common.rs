@@ -1,10 +1,28 @@
-use crate::service::Bundle;
+use std::{collections::BTreeMap, time::Duration};
+
+use crate::service::{Bundle, OverrideMode};
 
 /// Trait that defines the RPC service. `birdwatcher-daemon` start the server and `birdwatcher-cli` interract with it.
 ///
-/// Currently, it only has one method, `get_data`, that return the current state of the services.
-/// It is expected to be extended in the future with more methods, for example to trigger a manual check of a service, to reset the hysteresis state, or to change the configuration on the fly.
+/// `get_data` return the current state of the services, and `reload_config` reload the configuration file like SIGHUP does.
+/// `set_override` force the BIRD function of a service, e.g. to withdraw a route during a maintenance, and `is_drained` tells whether the drain file exists.
+/// `validation_errors` tells why the last generated files have been refused by their `validate_command`.
+/// It is expected to be extended in the future with more methods, for example to trigger a manual check of a service, or to reset the hysteresis state.
 #[tarpc::service]
 pub trait Insight {
     async fn get_data() -> Bundle;
+    /// Return the error if the new configuration is refused. The old one is then kept
+    async fn reload_config() -> Result<(), String>;
+    /// The override is removed after `expiry`, or never if `None`.
+    /// Return an error if no service defines the BIRD function `function_name`
+    async fn set_override(
+        function_name: String,
+        mode: OverrideMode,
+        expiry: Option<Duration>,
+        reason: String,
+    ) -> Result<(), String>;
+    /// True while the drain file exists. Always false if the config has no drain file
+    async fn is_drained() -> bool;
+    /// The error of each generated file whose last version has been refused, keyed by its path
+    async fn validation_errors() -> BTreeMap<String, String>;
 }
@@ -1,23 +1,88 @@
 use crate::{
-    config::Config,
     rpc::common::Insight,
-    service::{Bundle, ServiceState},
+    service::{Bundle, OverrideMode},
 };
 
-use std::{ops::Deref, sync::Arc};
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
 use tarpc::context;
+use tokio::sync::{mpsc, oneshot};
+
+/// Sent to the daemon, which owns the service tasks and the generated BIRD file.
+/// The result of the request is sent back through the `oneshot::Sender`
+pub enum DaemonRequest {
+    ReloadConfig(oneshot::Sender<Result<(), String>>),
+    SetOverride {
+        function_name: String,
+        mode: OverrideMode,
+        expiry: Option<Duration>,
+        reason: String,
+        result: oneshot::Sender<Result<(), String>>,
+    },
+}
 
 #[derive(Clone)]
 pub struct InsightServer {
-    pub service_states: Arc<std::sync::Mutex<Vec<ServiceState>>>,
-    pub config: Arc<Config>,
+    pub bundle: Arc<std::sync::Mutex<Bundle>>,
+    pub request_tx: mpsc::Sender<DaemonRequest>,
+}
+
+impl InsightServer {
+    async fn send(
+        &self,
+        request: impl FnOnce(oneshot::Sender<Result<(), String>>) -> DaemonRequest,
+    ) -> Result<(), String> {
+        let (tx, rx) = oneshot::channel();
+        self.request_tx
+            .send(request(tx))
+            .await
+            .map_err(|_| "The daemon is shutting down".to_owned())?;
+        rx.await
+            .map_err(|_| "The daemon is shutting down".to_owned())?
+    }
 }
 
 impl Insight for InsightServer {
     async fn get_data(self, _: context::Context) -> Bundle {
-        Bundle {
-            config: self.config.deref().clone(),
-            service_states: self.service_states.lock().unwrap().clone(),
-        }
+        self.bundle.lock().unwrap().clone()
+    }
+
+    async fn reload_config(self, _: context::Context) -> Result<(), String> {
+        self.send(DaemonRequest::ReloadConfig).await
+    }
+
+    async fn set_override(
+        self,
+        _: context::Context,
+        function_name: String,
+        mode: OverrideMode,
+        expiry: Option<Duration>,
+        reason: String,
+    ) -> Result<(), String> {
+        self.send(|result| DaemonRequest::SetOverride {
+            function_name,
+            mode,
+            expiry,
+            reason,
+            result,
+        })
+        .await
+    }
+
+    async fn is_drained(self, _: context::Context) -> bool {
+        self.bundle.lock().unwrap().drained
+    }
+
+    async fn validation_errors(self, _: context::Context) -> BTreeMap<String, String> {
+        let bundle = self.bundle.lock().unwrap();
+        bundle
+            .config
+            .outputs
+            .iter()
+            .zip(&bundle.outputs)
+            .filter_map(|(output, status)| {
+                let error = status.validation_error.clone()?;
+                Some((output.generated_file.path.clone(), error))
+            })
+            .collect()
     }
 }
@@ -1,63 +1,513 @@
 use serde::{Deserialize, Serialize};
-use std::time::Duration;
+use std::{
+    collections::BTreeMap,
+    time::{Duration, SystemTime},
+};
 
-use crate::config::Config;
+use crate::{
+    check::{Check, CheckOutcome, CheckStatus},
+    config::Config,
+    verify::Verification,
+};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Bundle {
     pub config: Config,
     pub service_states: Vec<ServiceState>,
+    /// Result of the last check of each service, including its sub-checks. `None` until the first check completes
+    pub last_outcomes: Vec<Option<CheckOutcome>>,
+    /// How long the last check of each service took, timeouts included
+    pub check_durations: Vec<Option<Duration>>,
+    /// Flap dampening of each service. Left untouched for services without `dampening`
+    pub dampening_states: Vec<DampeningState>,
+    /// True until the `startup_grace` of the service has elapsed since birdwatcher started
+    pub in_startup_grace: Vec<bool>,
+    /// Set by an operator, e.g. with `birdwatcher-cli drain`. The checks keep running, but are ignored
+    pub overrides: Vec<Option<Override>>,
+    /// True while the drain file of the config exists
+    pub drained: bool,
+    /// Status of each output of the config
+    pub outputs: Vec<OutputStatus>,
+    /// What the BIRD function of each service returns, dependencies included.
+    /// Derived from the fields above by `update_function_values`
+    pub function_values: Vec<FunctionValue>,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
-#[cfg_attr(test, derive(PartialEq,))]
+impl Bundle {
+    #[must_use]
+    pub fn new(config: Config, now: SystemTime) -> Bundle {
+        let service_states = config
+            .service_definitions
+            .iter()
+            .map(|def| ServiceState::initial(def, now))
+            .collect();
+        let in_startup_grace = config
+            .service_definitions
+            .iter()
+            .map(|def| !def.startup_grace.is_zero())
+            .collect();
+        let nb_of_services = config.service_definitions.len();
+        let nb_of_outputs = config.outputs.len();
+        let mut bundle = Bundle {
+            config,
+            service_states,
+            last_outcomes: vec![None; nb_of_services],
+            check_durations: vec![None; nb_of_services],
+            dampening_states: vec![DampeningState::new(now); nb_of_services],
+            in_startup_grace,
+            overrides: vec![None; nb_of_services],
+            drained: false,
+            outputs: vec![OutputStatus::default(); nb_of_outputs],
+            function_values: Vec::new(),
+        };
+        bundle.update_function_values();
+        bundle
+    }
+
+    /// The bundle to use after reloading the configuration.
+    /// A service keeps its state as long as its `function_name` does not change, even if its definition changed.
+    /// Added services start in their `initial_state`, without startup grace
+    #[must_use]
+    pub fn reconfigure(&self, config: Config, now: SystemTime) -> Bundle {
+        let mut bundle = Bundle::new(config, now);
+        bundle.drained = self.drained;
+        // An output keeps its status as long as its generated file does not change
+        for (output, status) in bundle.config.outputs.iter().zip(&mut bundle.outputs) {
+            if let Some(old_id) = self.output_id(&output.generated_file.path) {
+                status.clone_from(&self.outputs[old_id]);
+            }
+        }
+        for (id, def) in bundle.config.service_definitions.iter().enumerate() {
+            match service_id_by_function_name(&self.config.service_definitions, &def.function_name)
+            {
+                Some(old_id) => {
+                    bundle.service_states[id] = self.service_states[old_id].clone();
+                    bundle.last_outcomes[id].clone_from(&self.last_outcomes[old_id]);
+                    bundle.check_durations[id] = self.check_durations[old_id];
+                    bundle.dampening_states[id] = self.dampening_states[old_id].clone();
+                    bundle.in_startup_grace[id] = self.in_startup_grace[old_id];
+                    bundle.overrides[id].clone_from(&self.overrides[old_id]);
+                }
+                None => bundle.in_startup_grace[id] = false,
+            }
+        }
+        bundle.update_function_values();
+        bundle
+    }
+
+    /// Must be called after each modification of the state of a service
+    pub fn update_function_values(&mut self) {
+        // The drain file takes precedence over the overrides set with `birdwatcher-cli`
+        let forced = self
+            .config
+            .service_definitions
+            .iter()
+            .zip(&self.overrides)
+            .map(|(def, r#override)| match &self.config.drain_file {
+                Some(drain_file) if self.drained && drain_file.drains(def) => {
+                    Some(Forced::Drained {
+                        drain_file: drain_file.path.clone(),
+                    })
+                }
+                _ => r#override.clone().map(Forced::Override),
+            })
+            .collect::<Vec<_>>();
+        self.function_values = function_values(
+            &self.config.service_definitions,
+            &self.service_states,
+            &self.dampening_states,
+            &self.in_startup_grace,
+            &forced,
+        );
+    }
+
+    /// What the `int_function` of the service `id` returns. `None` if it has none
+    #[must_use]
+    pub fn int_value(&self, id: usize) -> Option<i32> {
+        let def = &self.config.service_definitions[id];
+        def.int_function.as_ref().map(|int_function| {
+            int_function.value(
+                def,
+                &self.service_states[id],
+                self.check_durations[id],
+                self.function_values[id].value,
+            )
+        })
+    }
+
+    /// Index of the output writing the generated file `path`
+    #[must_use]
+    pub fn output_id(&self, path: &str) -> Option<usize> {
+        self.config
+            .outputs
+            .iter()
+            .position(|output| output.generated_file.path == path)
+    }
+
+    /// Return true if the drain status changed
+    pub fn set_drained(&mut self, drained: bool) -> bool {
+        let changed = self.drained != drained;
+        self.drained = drained;
+        self.update_function_values();
+        changed
+    }
+
+    /// # Errors
+    ///
+    /// Will return `Err` if no service defines the BIRD function `function_name`
+    pub fn set_override(
+        &mut self,
+        function_name: &str,
+        r#override: Option<Override>,
+    ) -> Result<(), String> {
+        let id = service_id_by_function_name(&self.config.service_definitions, function_name)
+            .ok_or_else(|| format!("No service defines the BIRD function '{function_name}'"))?;
+        self.overrides[id] = r#override;
+        self.update_function_values();
+        Ok(())
+    }
+
+    /// Remove the overrides which have expired. Return true if any has been removed
+    pub fn expire_overrides(&mut self, now: SystemTime) -> bool {
+        let mut expired = false;
+        for r#override in &mut self.overrides {
+            if r#override
+                .as_ref()
+                .is_some_and(|o| o.until.is_some_and(|until| until <= now))
+            {
+                *r#override = None;
+                expired = true;
+            }
+        }
+        if expired {
+            self.update_function_values();
+        }
+        expired
+    }
+
+    /// When the next override expires
+    #[must_use]
+    pub fn next_override_expiry(&self) -> Option<SystemTime> {
+        self.overrides
+            .iter()
+            .flatten()
+            .filter_map(|o| o.until)
+            .min()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct ServiceDefinition {
     /// Used for logs only
     pub service_name: String,
     /// The name of the generated `bird` function
     pub function_name: String,
-    pub command: String,
-    pub args: Vec<String>,
+    pub check: Check,
     pub interval: Duration,
     pub command_timeout: Duration,
     /// Number of consecutive failure to consider the service unhealthy
     pub fall: u32,
     /// Number of consecutive failure to consider the service healthy
     pub rise: u32,
+    /// Once up, the service stays up for at least this time, whatever the check results
+    pub min_up_time: Duration,
+    /// Once down, the service stays down for at least this time, whatever the check results
+    pub min_down_time: Duration,
+    /// The state of the service when birdwatcher starts
+    pub initial_state: InitialState,
+    /// After birdwatcher starts, the check results are taken into account, but the BIRD function returns false during this time
+    pub startup_grace: Duration,
+    /// How a `CheckStatus::Warning` is counted
+    pub on_warning: OnWarning,
+    pub dampening: Option<Dampening>,
+    /// `service_name` of the services which must be up for this one to be up.
+    /// The config guarantees each one refers to exactly one service, without cycles
+    pub depends_on: Vec<String>,
+    /// Used to select the services withdrawn by the drain file, and those rendered by each output with `tags`
+    pub tags: Vec<String>,
+    /// Routes announced thanks to this service. Compared to what BIRD exports if the config has `verify`
+    pub prefixes: Vec<String>,
+    /// Rendered instead of the default BIRD function. The `template` of the generated file is used if `None`
+    pub template: Option<String>,
+    /// Custom variables of the template
+    pub variables: BTreeMap<String, String>,
+    /// Generated along the BIRD function of the service, e.g. to set `bgp_med`
+    pub int_function: Option<IntFunction>,
+}
+
+/// Index of the service whose BIRD function is `function_name` in `service_definitions`
+#[must_use]
+pub fn service_id_by_function_name(
+    service_definitions: &[ServiceDefinition],
+    function_name: &str,
+) -> Option<usize> {
+    service_definitions
+        .iter()
+        .position(|def| def.function_name == function_name)
+}
+
+/// Index of the service named `service_name` in `service_definitions`
+#[must_use]
+pub fn service_id(service_definitions: &[ServiceDefinition], service_name: &str) -> Option<usize> {
+    service_definitions
+        .iter()
+        .position(|def| def.service_name == service_name)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InitialState {
+    /// Down, as if the service had just gone down: `rise` successes are needed, after `min_down_time`
+    Down,
+    /// Up, the service is announced before its first check
+    Up,
+    /// Down, but only one success is enough to go up
+    RiseOnce,
+    /// Down, `rise` consecutive successes are needed to go up
+    FullRise,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnWarning {
+    Success,
+    Failure,
+    /// Counts as a success, but the service is flagged as degraded while the last result is a warning.
+    /// An additional `<function_name>_degraded()` BIRD function exposes this flag
+    Degraded,
+}
+
+/// A BIRD function returning an int between `worst` and `best`, depending on how healthy the service is.
+/// It returns `worst` while the BIRD function of the service returns false
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct IntFunction {
+    pub function_name: String,
+    pub source: IntSource,
+    /// Returned by a perfectly healthy service. Lower than `worst` for a MED, higher for a local preference
+    pub best: i32,
+    pub worst: i32,
+    /// The value is a multiple of `step` away from `worst`, so that small variations do not reload BIRD
+    pub step: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IntSource {
+    /// The hysteresis state, like the `birdwatcher_service_hysteresis_state` metric:
+    /// `best` without failures, toward `worst` as the failures approach `fall`
+    Health,
+    /// The duration of the last check: `best` up to `best_latency`, `worst` from `worst_latency`
+    Latency {
+        best_latency: Duration,
+        worst_latency: Duration,
+    },
+}
+
+impl IntFunction {
+    /// The value returned by the function, from the state of the service and the value of its BIRD function
+    #[must_use]
+    pub fn value(
+        &self,
+        service_def: &ServiceDefinition,
+        service_state: &ServiceState,
+        check_duration: Option<Duration>,
+        function_value: bool,
+    ) -> i32 {
+        let nanos = |duration: Duration| i128::try_from(duration.as_nanos()).unwrap_or(i128::MAX);
+        // Fraction of the way from `worst` to `best`
+        let (numerator, denominator) = match (function_value, self.source, service_state) {
+            (false, _, _) => (0, 1),
+            (true, IntSource::Health, ServiceState::Failure { nb_of_success, .. }) => {
+                (i128::from(*nb_of_success), i128::from(service_def.rise))
+            }
+            (true, IntSource::Health, ServiceState::Success { nb_of_failure, .. }) => (
+                i128::from(service_def.fall.saturating_sub(*nb_of_failure)),
+                i128::from(service_def.fall),
+            ),
+            (
+                true,
+                IntSource::Latency {
+                    best_latency,
+                    worst_latency,
+                },
+                _,
+            ) => check_duration.map_or((0, 1), |duration| {
+                (
+                    nanos(worst_latency.saturating_sub(duration)),
+                    nanos(worst_latency.saturating_sub(best_latency)),
+                )
+            }),
+        };
+        let (best, worst) = (i128::from(self.best), i128::from(self.worst));
+        // Rounded to the nearest step, half away from zero
+        let scaled = numerator * (best - worst);
+        let unit = denominator.max(1) * i128::from(self.step.max(1));
+        let steps = (2 * scaled + unit * scaled.signum()) / (2 * unit);
+        let value = (worst + steps * i128::from(self.step)).clamp(best.min(worst), best.max(worst));
+        // Always within `best` and `worst`
+        i32::try_from(value).unwrap_or(self.worst)
+    }
+}
+
+/// BGP-style route flap dampening (RFC 2439), on top of the fall/rise mecanism.
+/// Each time the service goes up or down, its penalty increases. The penalty decays exponentially over time.
+/// While suppressed, the BIRD function of the service returns false
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct Dampening {
+    /// Added to the penalty on each transition
+    pub penalty: f64,
+    /// Time for the penalty to be divided by 2
+    pub half_life: Duration,
+    /// The service is suppressed when the penalty goes above this threshold
+    pub suppress: f64,
+    /// A suppressed service is reused when the penalty goes below this threshold
+    pub reuse: f64,
+    /// The penalty is capped to this value, which bounds how long a service stays suppressed
+    pub max_penalty: f64,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DampeningState {
+    pub penalty: f64,
+    pub suppressed: bool,
+    /// When `penalty` was last decayed
+    pub updated_at: SystemTime,
+}
+
+impl DampeningState {
+    #[must_use]
+    pub fn new(now: SystemTime) -> DampeningState {
+        DampeningState {
+            penalty: 0.0,
+            suppressed: false,
+            updated_at: now,
+        }
+    }
+
+    /// Decay the penalty until `now`, and add the penalty of a transition if `flapped`.
+    ///
+    /// Return whether the service has been suppressed or reused
+    #[must_use]
+    pub fn update(&mut self, dampening: &Dampening, flapped: bool, now: SystemTime) -> bool {
+        let elapsed = now.duration_since(self.updated_at).unwrap_or_default();
+        self.penalty *= 0.5_f64.powf(elapsed.as_secs_f64() / dampening.half_life.as_secs_f64());
+        self.updated_at = now;
+        if flapped {
+            self.penalty = (self.penalty + dampening.penalty).min(dampening.max_penalty);
+        }
+
+        let was_suppressed = self.suppressed;
+        if self.penalty > dampening.suppress {
+            self.suppressed = true;
+        } else if self.penalty < dampening.reuse {
+            self.suppressed = false;
+        }
+        self.suppressed != was_suppressed
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub enum ServiceState {
     /// In `Failure` state, count the number of success.
     /// When the number goes above `rise`, switch to `Success` state
     /// Any failure reset the counter to 0
-    Failure { nb_of_success: u32 },
+    Failure {
+        nb_of_success: u32,
+        /// When the service went down. `None` if it has been down since birdwatcher started
+        last_transition: Option<SystemTime>,
+    },
     /// In `Success` state, count the number of failure.
     /// When the number goes above `fall`, switch to `Failure` state
     /// Any failure reset the counter to 0
-    Success { nb_of_failure: u32 },
+    /// `degraded` is true if the last success was a warning, for a service with `OnWarning::Degraded`
+    Success {
+        nb_of_failure: u32,
+        degraded: bool,
+        /// When the service went up
+        last_transition: Option<SystemTime>,
+    },
+}
+
+/// Whether a service which changed its state at `last_transition` must keep it until at least `min_time` has elapsed
+fn is_held(last_transition: Option<SystemTime>, min_time: Duration, now: SystemTime) -> bool {
+    last_transition.is_some_and(|t| now.duration_since(t).unwrap_or_default() < min_time)
 }
 
 impl ServiceState {
+    /// The state of a service when birdwatcher starts at `now`
+    #[must_use]
+    pub fn initial(service_def: &ServiceDefinition, now: SystemTime) -> ServiceState {
+        match service_def.initial_state {
+            InitialState::Down => ServiceState::Failure {
+                nb_of_success: 0,
+                last_transition: Some(now),
+            },
+            InitialState::Up => ServiceState::Success {
+                nb_of_failure: 0,
+                degraded: false,
+                last_transition: None,
+            },
+            InitialState::RiseOnce => ServiceState::Failure {
+                nb_of_success: service_def.rise - 1,
+                last_transition: None,
+            },
+            InitialState::FullRise => ServiceState::Failure {
+                nb_of_success: 0,
+                last_transition: None,
+            },
+        }
+    }
+
+    #[must_use]
+    pub fn is_up(&self) -> bool {
+        matches!(self, ServiceState::Success { .. })
+    }
+
     /// Handle the fall/rise mecanism where multiple success/failure must happen
-    /// consecutivly to cause a state change
+    /// consecutivly to cause a state change.
+    /// After a change, the state is kept for at least `min_up_time`/`min_down_time`:
+    /// until then, the counter stops at `rise`/`fall`, and the change happens on the first check result after the hold time.
+    ///
+    /// Also return whether the generated BIRD functions need to be updated:
+    /// the service goes up or down, or becomes degraded or not
     #[must_use]
     pub fn update_with(
         &self,
-        return_value: bool,
+        status: CheckStatus,
         service_def: &ServiceDefinition,
+        now: SystemTime,
     ) -> (ServiceState, bool) {
+        let (return_value, degraded) = match (status, service_def.on_warning) {
+            (CheckStatus::Success, _) | (CheckStatus::Warning, OnWarning::Success) => (true, false),
+            (CheckStatus::Failure, _) | (CheckStatus::Warning, OnWarning::Failure) => {
+                (false, false)
+            }
+            (CheckStatus::Warning, OnWarning::Degraded) => (true, true),
+        };
         match self {
-            ServiceState::Failure { nb_of_success } => {
+            ServiceState::Failure {
+                nb_of_success,
+                last_transition,
+            } => {
                 if return_value {
-                    if nb_of_success + 1 >= service_def.rise {
+                    if nb_of_success + 1 >= service_def.rise
+                        && !is_held(*last_transition, service_def.min_down_time, now)
+                    {
                         // Switch to rise
-                        (ServiceState::Success { nb_of_failure: 0 }, true)
+                        (
+                            ServiceState::Success {
+                                nb_of_failure: 0,
+                                degraded,
+                                last_transition: Some(now),
+                            },
+                            true,
+                        )
                     } else {
-                        // Another success, but not enough to rise
+                        // Another success, but not enough to rise, or too soon
                         (
                             ServiceState::Failure {
-                                nb_of_success: nb_of_success + 1,
+                                nb_of_success: (nb_of_success + 1).min(service_def.rise),
+                                last_transition: *last_transition,
                             },
                             false,
                         )
@@ -65,23 +515,50 @@ impl ServiceState {
                 } else
                 /* A failure on failure */
                 {
-                    (ServiceState::Failure { nb_of_success: 0 }, false)
+                    (
+                        ServiceState::Failure {
+                            nb_of_success: 0,
+                            last_transition: *last_transition,
+                        },
+                        false,
+                    )
                 }
             }
-            ServiceState::Success { nb_of_failure } => {
+            ServiceState::Success {
+                nb_of_failure,
+                degraded: was_degraded,
+                last_transition,
+            } => {
                 if return_value {
-                    (ServiceState::Success { nb_of_failure: 0 }, false)
+                    (
+                        ServiceState::Success {
+                            nb_of_failure: 0,
+                            degraded,
+                            last_transition: *last_transition,
+                        },
+                        degraded != *was_degraded,
+                    )
                 } else
                 /* A new failure. Should we switch to Failure? */
                 {
                     // Yes, switch to Failure
-                    if nb_of_failure + 1 >= service_def.fall {
-                        (ServiceState::Failure { nb_of_success: 0 }, true)
+                    if nb_of_failure + 1 >= service_def.fall
+                        && !is_held(*last_transition, service_def.min_up_time, now)
+                    {
+                        (
+                            ServiceState::Failure {
+                                nb_of_success: 0,
+                                last_transition: Some(now),
+                            },
+                            true,
+                        )
                     } else {
-                        // No. Another failure, but not enough to fall
+                        // No. Another failure, but not enough to fall, or too soon
                         (
                             ServiceState::Success {
-                                nb_of_failure: nb_of_failure + 1,
+                                nb_of_failure: (nb_of_failure + 1).min(service_def.fall),
+                                degraded: *was_degraded,
+                                last_transition: *last_transition,
                             },
                             false,
                         )
@@ -91,3 +568,842 @@ impl ServiceState {
         }
     }
 }
+
+/// What an operator asks for a service
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum OverrideMode {
+    ForcedUp,
+    ForcedDown,
+    /// Remove the override, the BIRD function follows the checks again
+    Auto,
+}
+
+/// What happened to a generated file and its BIRD instance
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct OutputStatus {
+    /// Why `validate_command` refused the last generated file. `None` once a generated file is accepted
+    pub validation_error: Option<String>,
+    /// Outcome of the last reload of BIRD, retries included. `None` until BIRD is reloaded for the first time
+    pub last_reload: Option<ReloadStatus>,
+    /// Comparison of the routes exported by BIRD with the function values, after the last reload. `None` until the first one
+    pub verification: Option<Verification>,
+}
+
+/// Outcome of a reload of BIRD
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ReloadStatus {
+    pub at: SystemTime,
+    /// `None` if the reload command succeeded. Otherwise BIRD may still use a previous generated file
+    pub error: Option<String>,
+}
+
+/// Forces the BIRD function of a service, whatever its checks, dependencies or dampening say
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Override {
+    /// The value returned by the BIRD function
+    pub value: bool,
+    /// The override is removed at this time. `None` to keep it until it is removed by an operator
+    pub until: Option<SystemTime>,
+    /// Given by the operator, e.g. "maintenance"
+    pub reason: String,
+}
+
+impl Override {
+    /// `None` for `OverrideMode::Auto`
+    #[must_use]
+    pub fn new(mode: OverrideMode, until: Option<SystemTime>, reason: String) -> Option<Override> {
+        let value = match mode {
+            OverrideMode::ForcedUp => true,
+            OverrideMode::ForcedDown => false,
+            OverrideMode::Auto => return None,
+        };
+        Some(Override {
+            value,
+            until,
+            reason,
+        })
+    }
+}
+
+/// Why the BIRD function of a service ignores its checks
+#[derive(Debug, Clone, PartialEq)]
+pub enum Forced {
+    /// Set by an operator with `birdwatcher-cli`
+    Override(Override),
+    /// The drain file exists and selects the service, which is withdrawn
+    Drained { drain_file: String },
+}
+
+impl Forced {
+    fn function_value(&self) -> FunctionValue {
+        match self {
+            Forced::Override(r#override) => FunctionValue {
+                value: r#override.value,
+                reason: format!(
+                    "forced {} by operator: {}",
+                    if r#override.value { "up" } else { "down" },
+                    r#override.reason
+                ),
+            },
+            Forced::Drained { drain_file } => FunctionValue {
+                value: false,
+                reason: format!("drained: {drain_file} exists"),
+            },
+        }
+    }
+}
+
+/// The value returned by the generated BIRD function of a service
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct FunctionValue {
+    pub value: bool,
+    /// Why the function returns this value, e.g. because a dependency is down
+    pub reason: String,
+}
+
+/// Combine the state of each service with its startup grace, its flap dampening and the state of its dependencies.
+/// A service is down if any of its dependencies, direct or indirect, is down.
+/// An override or the drain file takes precedence over everything else, and is seen by the services depending on this one
+#[must_use]
+pub fn function_values(
+    service_definitions: &[ServiceDefinition],
+    service_states: &[ServiceState],
+    dampening_states: &[DampeningState],
+    in_startup_grace: &[bool],
+    forced: &[Option<Forced>],
+) -> Vec<FunctionValue> {
+    fn compute(
+        id: usize,
+        service_definitions: &[ServiceDefinition],
+        service_states: &[ServiceState],
+        dampening_states: &[DampeningState],
+        in_startup_grace: &[bool],
+        forced: &[Option<Forced>],
+        values: &mut [Option<FunctionValue>],
+    ) -> FunctionValue {
+        if let Some(value) = &values[id] {
+            return value.clone();
+        }
+        let value = if let Some(forced) = &forced[id] {
+            forced.function_value()
+        } else {
+            match service_states[id] {
+                ServiceState::Failure { .. } => FunctionValue {
+                    value: false,
+                    reason: "check failing".to_owned(),
+                },
+                ServiceState::Success { .. } if in_startup_grace[id] => FunctionValue {
+                    value: false,
+                    reason: "in startup grace period".to_owned(),
+                },
+                ServiceState::Success { .. } if dampening_states[id].suppressed => FunctionValue {
+                    value: false,
+                    reason: format!(
+                        "suppressed by flap dampening (penalty {:.0})",
+                        dampening_states[id].penalty
+                    ),
+                },
+                ServiceState::Success { .. } => service_definitions[id]
+                    .depends_on
+                    .iter()
+                    .find(|dependency| {
+                        let dependency_id = service_id(service_definitions, dependency)
+                            .expect("dependencies are checked when loading the config");
+                        !compute(
+                            dependency_id,
+                            service_definitions,
+                            service_states,
+                            dampening_states,
+                            in_startup_grace,
+                            forced,
+                            values,
+                        )
+                        .value
+                    })
+                    .map_or_else(
+                        || FunctionValue {
+                            value: true,
+                            reason: "check passing".to_owned(),
+                        },
+                        |dependency| FunctionValue {
+                            value: false,
+                            reason: format!("dependency '{dependency}' is down"),
+                        },
+                    ),
+            }
+        };
+        values[id] = Some(value.clone());
+        value
+    }
+
+    let mut values = vec![None; service_definitions.len()];
+    (0..service_definitions.len())
+        .map(|id| {
+            compute(
+                id,
+                service_definitions,
+                service_states,
+                dampening_states,
+                in_startup_grace,
+                forced,
+                &mut values,
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::BTreeMap,
+        time::{Duration, SystemTime},
+    };
+
+    use super::{
+        function_values, Bundle, Dampening, DampeningState, FunctionValue, InitialState,
+        IntFunction, IntSource, OnWarning, Override, OverrideMode, ServiceDefinition, ServiceState,
+    };
+
+    /// For the tests which do not depend on the time
+    const T0: SystemTime = SystemTime::UNIX_EPOCH;
+    use crate::{
+        check::{
+            command::{CommandCheck, OutputMatch, ResultMode},
+            Check,
+            CheckStatus::{Failure, Success, Warning},
+        },
+        config::{
+            BirdReload, Config, DrainFile, GeneratedContent, GeneratedFile, Output, Reload,
+            Shutdown, ShutdownAction,
+        },
+    };
+
+    fn service_def(fall: u32, rise: u32, on_warning: OnWarning) -> ServiceDefinition {
+        ServiceDefinition {
+            service_name: "service".to_owned(),
+            function_name: "service_is_up".to_owned(),
+            check: Check::Command(CommandCheck {
+                command: "/bin/true".to_owned(),
+                args: vec![],
+                result_mode: ResultMode::ExitCode,
+                success_exit_codes: vec![0],
+                stdout: OutputMatch::default(),
+                stderr: OutputMatch::default(),
+            }),
+            interval: Duration::from_secs(1),
+            command_timeout: Duration::from_secs(1),
+            fall,
+            rise,
+            min_up_time: Duration::ZERO,
+            min_down_time: Duration::ZERO,
+            initial_state: InitialState::RiseOnce,
+            startup_grace: Duration::ZERO,
+            on_warning,
+            dampening: None,
+            depends_on: vec![],
+            tags: vec![],
+            prefixes: vec![],
+            template: None,
+            variables: BTreeMap::new(),
+            int_function: None,
+        }
+    }
+
+    fn config(service_definitions: Vec<ServiceDefinition>) -> Config {
+        Config {
+            outputs: vec![Output {
+                generated_file: GeneratedFile {
+                    path: "birdwatcher_generated.conf".to_owned(),
+                    function_return_type: true,
+                    mode: None,
+                    uid: None,
+                    gid: None,
+                    template: None,
+                    variables: BTreeMap::new(),
+                    content: GeneratedContent::Functions,
+                    route: "blackhole".to_owned(),
+                },
+                reload: Reload {
+                    method: BirdReload::Command {
+                        command: "/bin/true".to_owned(),
+                        args: vec![],
+                    },
+                    timeout: Duration::from_secs(1),
+                    debounce: Duration::ZERO,
+                    validate_command: None,
+                    validate_command_args: vec![],
+                    retries: 0,
+                    retry_backoff: Duration::from_secs(1),
+                    max_retry_backoff: Duration::from_secs(30),
+                    reconcile_interval: Duration::from_secs(30),
+                },
+                verify: None,
+                services: None,
+                tags: None,
+            }],
+            state_file: None,
+            drain_file: None,
+            shutdown: Shutdown {
+                action: ShutdownAction::Keep,
+                drain_delay: Duration::ZERO,
+            },
+            service_definitions,
+        }
+    }
+
+    #[test]
+    fn rise_after_consecutive_success() {
+        let def = service_def(2, 3, OnWarning::Failure);
+        let state = ServiceState::Failure {
+            nb_of_success: 0,
+            last_transition: None,
+        };
+
+        let (state, changed) = state.update_with(Success, &def, T0);
+        assert!(!changed);
+        let (state, changed) = state.update_with(Success, &def, T0);
+        assert!(!changed);
+        let (state, changed) = state.update_with(Success, &def, T0);
+        assert!(changed);
+        assert!(matches!(
+            state,
+            ServiceState::Success {
+                nb_of_failure: 0,
+                degraded: false,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn failure_resets_rise_counter() {
+        let def = service_def(2, 3, OnWarning::Failure);
+        let state = ServiceState::Failure {
+            nb_of_success: 0,
+            last_transition: None,
+        };
+
+        let (state, _) = state.update_with(Success, &def, T0);
+        let (state, _) = state.update_with(Success, &def, T0);
+        let (state, changed) = state.update_with(Failure, &def, T0);
+        assert!(!changed);
+        assert!(matches!(
+            state,
+            ServiceState::Failure {
+                nb_of_success: 0,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn fall_after_consecutive_failure() {
+        let def = service_def(2, 3, OnWarning::Failure);
+        let state = ServiceState::Success {
+            nb_of_failure: 0,
+            degraded: false,
+            last_transition: None,
+        };
+
+        let (state, changed) = state.update_with(Failure, &def, T0);
+        assert!(!changed);
+        let (state, changed) = state.update_with(Failure, &def, T0);
+        assert!(changed);
+        assert!(matches!(
+            state,
+            ServiceState::Failure {
+                nb_of_success: 0,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn warning_counted_as_configured() {
+        let up = ServiceState::Success {
+            nb_of_failure: 0,
+            degraded: false,
+            last_transition: None,
+        };
+
+        let (state, changed) = up.update_with(Warning, &service_def(1, 1, OnWarning::Failure), T0);
+        assert!(changed);
+        assert!(matches!(state, ServiceState::Failure { .. }));
+
+        let (state, changed) = up.update_with(Warning, &service_def(1, 1, OnWarning::Success), T0);
+        assert!(!changed);
+        assert!(matches!(
+            state,
+            ServiceState::Success {
+                degraded: false,
+                ..
+            }
+        ));
+
+        let def = service_def(1, 1, OnWarning::Degraded);
+        let (state, changed) = up.update_with(Warning, &def, T0);
+        assert!(changed);
+        assert!(matches!(
+            state,
+            ServiceState::Success { degraded: true, .. }
+        ));
+        let (state, changed) = state.update_with(Success, &def, T0);
+        assert!(changed);
+        assert!(matches!(
+            state,
+            ServiceState::Success {
+                degraded: false,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn down_dependency_forces_service_down() {
+        let named = |name: &str, depends_on: &[&str]| ServiceDefinition {
+            service_name: name.to_owned(),
+            depends_on: depends_on.iter().map(|&d| d.to_owned()).collect(),
+            ..service_def(1, 1, OnWarning::Failure)
+        };
+        let definitions = [
+            named("frontend", &["backend"]),
+            named("backend", &["database"]),
+            named("database", &[]),
+        ];
+        let up = ServiceState::Success {
+            nb_of_failure: 0,
+            degraded: false,
+            last_transition: None,
+        };
+        let down = ServiceState::Failure {
+            nb_of_success: 0,
+            last_transition: None,
+        };
+        let not_dampened = vec![DampeningState::new(SystemTime::now()); 3];
+
+        let values = function_values(
+            &definitions,
+            &[up.clone(), up.clone(), up.clone()],
+            &not_dampened,
+            &[false; 3],
+            &[None, None, None],
+        );
+        assert!(values.iter().all(|v| v.value));
+
+        let values = function_values(
+            &definitions,
+            &[up.clone(), up.clone(), down.clone()],
+            &not_dampened,
+            &[false; 3],
+            &[None, None, None],
+        );
+        assert_eq!(
+            values,
+            [
+                FunctionValue {
+                    value: false,
+                    reason: "dependency 'backend' is down".to_owned()
+                },
+                FunctionValue {
+                    value: false,
+                    reason: "dependency 'database' is down".to_owned()
+                },
+                FunctionValue {
+                    value: false,
+                    reason: "check failing".to_owned()
+                },
+            ]
+        );
+
+        let values = function_values(
+            &definitions,
+            &[down, up.clone(), up],
+            &not_dampened,
+            &[false; 3],
+            &[None, None, None],
+        );
+        assert_eq!(
+            values.iter().map(|v| v.value).collect::<Vec<_>>(),
+            [false, true, true]
+        );
+    }
+
+    #[test]
+    fn dampening_suppresses_then_reuses() {
+        let dampening = Dampening {
+            penalty: 1000.0,
+            half_life: Duration::from_mins(1),
+            suppress: 2000.0,
+            reuse: 750.0,
+            max_penalty: 4000.0,
+        };
+        let start = SystemTime::UNIX_EPOCH;
+        let after = |secs| start + Duration::from_secs(secs);
+        let mut state = DampeningState::new(start);
+
+        // Two quick flaps are tolerated, the third one suppresses the service
+        assert!(!state.update(&dampening, true, after(0)));
+        assert!(!state.update(&dampening, true, after(1)));
+        assert!(state.update(&dampening, true, after(2)));
+        assert!(state.suppressed);
+
+        // Still suppressed while the penalty decays between the thresholds
+        assert!(!state.update(&dampening, false, after(62)));
+        assert!(state.suppressed);
+        assert!((state.penalty - 1500.0).abs() < 20.0, "{}", state.penalty);
+
+        // Reused once the penalty goes below `reuse`
+        assert!(state.update(&dampening, false, after(122)));
+        assert!(!state.suppressed);
+
+        // The penalty is capped
+        for i in 0..10 {
+            let _ = state.update(&dampening, true, after(200 + i));
+        }
+        assert!(state.penalty <= dampening.max_penalty);
+    }
+
+    #[test]
+    fn state_held_after_transition() {
+        let def = ServiceDefinition {
+            min_up_time: Duration::from_secs(30),
+            min_down_time: Duration::from_secs(10),
+            ..service_def(1, 2, OnWarning::Failure)
+        };
+        let after = |secs| T0 + Duration::from_secs(secs);
+        let state = ServiceState::Failure {
+            nb_of_success: 1,
+            last_transition: None,
+        };
+
+        // Not held at startup
+        let (state, changed) = state.update_with(Success, &def, after(0));
+        assert!(changed);
+        assert!(matches!(
+            state,
+            ServiceState::Success {
+                last_transition: Some(t),
+                ..
+            } if t == after(0)
+        ));
+
+        // Failures before `min_up_time` do not bring the service down
+        let (state, changed) = state.update_with(Failure, &def, after(10));
+        assert!(!changed);
+        let (state, changed) = state.update_with(Failure, &def, after(20));
+        assert!(!changed);
+        assert!(matches!(
+            state,
+            ServiceState::Success {
+                nb_of_failure: 1,
+                ..
+            }
+        ));
+
+        // The first failure after `min_up_time` does
+        let (state, changed) = state.update_with(Failure, &def, after(30));
+        assert!(changed);
+        assert!(!state.is_up());
+
+        // Same for going up
+        let (state, _) = state.update_with(Success, &def, after(32));
+        let (state, changed) = state.update_with(Success, &def, after(34));
+        assert!(!changed);
+        let (state, changed) = state.update_with(Success, &def, after(40));
+        assert!(changed);
+        assert!(state.is_up());
+    }
+
+    #[test]
+    fn initial_state() {
+        let def = |initial_state| ServiceDefinition {
+            initial_state,
+            min_down_time: Duration::from_secs(10),
+            ..service_def(1, 3, OnWarning::Failure)
+        };
+        let after = |secs| T0 + Duration::from_secs(secs);
+        let nb_of_success_to_rise = |initial_state| {
+            let def = def(initial_state);
+            let mut state = ServiceState::initial(&def, T0);
+            (1..100)
+                .find(|&i| {
+                    state = state.update_with(Success, &def, after(i)).0;
+                    state.is_up()
+                })
+                .unwrap()
+        };
+
+        assert!(ServiceState::initial(&def(InitialState::Up), T0).is_up());
+        assert_eq!(nb_of_success_to_rise(InitialState::RiseOnce), 1);
+        assert_eq!(nb_of_success_to_rise(InitialState::FullRise), 3);
+        // Held down during `min_down_time`
+        assert_eq!(nb_of_success_to_rise(InitialState::Down), 10);
+    }
+
+    #[test]
+    fn startup_grace_keeps_function_down() {
+        let definitions = [service_def(1, 1, OnWarning::Failure)];
+        let up = ServiceState::Success {
+            nb_of_failure: 0,
+            degraded: false,
+            last_transition: None,
+        };
+        let not_dampened = [DampeningState::new(T0)];
+
+        let values = function_values(
+            &definitions,
+            std::slice::from_ref(&up),
+            &not_dampened,
+            &[true],
+            &[None],
+        );
+        assert_eq!(
+            values,
+            [FunctionValue {
+                value: false,
+                reason: "in startup grace period".to_owned()
+            }]
+        );
+        let values = function_values(&definitions, &[up], &not_dampened, &[false], &[None]);
+        assert!(values[0].value);
+    }
+
+    #[test]
+    fn reconfigure_keeps_state_of_unchanged_function_name() {
+        let def = |function_name: &str, fall| ServiceDefinition {
+            function_name: function_name.to_owned(),
+            startup_grace: Duration::from_secs(10),
+            ..service_def(fall, 1, OnWarning::Failure)
+        };
+        let up = ServiceState::Success {
+            nb_of_failure: 0,
+            degraded: false,
+            last_transition: Some(T0),
+        };
+
+        let mut bundle = Bundle::new(config(vec![def("kept", 1), def("removed", 1)]), T0);
+        bundle.service_states = vec![up.clone(), up.clone()];
+        bundle.in_startup_grace = vec![false, false];
+        bundle.update_function_values();
+
+        // "kept" changes its definition, but not its function name
+        let bundle = bundle.reconfigure(config(vec![def("added", 1), def("kept", 3)]), T0);
+        assert_eq!(bundle.config.service_definitions[1].fall, 3);
+        assert_eq!(
+            bundle.service_states,
+            [ServiceState::initial(&def("added", 1), T0), up]
+        );
+        // A service added by a reload does not wait for a startup grace period
+        assert_eq!(bundle.in_startup_grace, [false, false]);
+        assert!(bundle.function_values[1].value);
+    }
+
+    #[test]
+    fn reconfigure_keeps_status_of_unchanged_generated_file() {
+        let with_outputs = |paths: &[&str]| {
+            let mut config = config(vec![service_def(1, 1, OnWarning::Failure)]);
+            let output = config.outputs.pop().unwrap();
+            config.outputs = paths
+                .iter()
+                .map(|path| {
+                    let mut output = output.clone();
+                    output.generated_file.path = (*path).to_owned();
+                    output
+                })
+                .collect();
+            config
+        };
+
+        let mut bundle = Bundle::new(with_outputs(&["bird4.conf", "bird6.conf"]), T0);
+        bundle.outputs[1].validation_error = Some("syntax error".to_owned());
+
+        let bundle = bundle.reconfigure(with_outputs(&["bird6.conf", "bird.conf"]), T0);
+        assert_eq!(bundle.output_id("bird6.conf"), Some(0));
+        assert_eq!(
+            bundle.outputs[0].validation_error.as_deref(),
+            Some("syntax error")
+        );
+        assert_eq!(bundle.outputs[1].validation_error, None);
+        assert_eq!(bundle.output_id("bird4.conf"), None);
+    }
+
+    #[test]
+    fn int_function_follows_health() {
+        let def = service_def(4, 2, OnWarning::Failure);
+        // A MED: the lower, the more preferred
+        let med = IntFunction {
+            function_name: "service_med".to_owned(),
+            source: IntSource::Health,
+            best: 0,
+            worst: 200,
+            step: 1,
+        };
+        let up = |nb_of_failure| ServiceState::Success {
+            nb_of_failure,
+            degraded: false,
+            last_transition: None,
+        };
+        let down = |nb_of_success| ServiceState::Failure {
+            nb_of_success,
+            last_transition: None,
+        };
+
+        assert_eq!(med.value(&def, &up(0), None, true), 0);
+        assert_eq!(med.value(&def, &up(1), None, true), 50);
+        assert_eq!(med.value(&def, &up(3), None, true), 150);
+        // E.g. forced up by an override
+        assert_eq!(med.value(&def, &down(1), None, true), 100);
+        assert_eq!(med.value(&def, &up(0), None, false), 200);
+
+        // Multiples of 40 away from 200: 50 is rounded to 40, and 150 to 160
+        let med = IntFunction { step: 40, ..med };
+        assert_eq!(med.value(&def, &up(1), None, true), 40);
+        assert_eq!(med.value(&def, &up(3), None, true), 160);
+        // -40 is out of the range
+        let med = IntFunction { step: 120, ..med };
+        assert_eq!(med.value(&def, &up(0), None, true), 0);
+    }
+
+    #[test]
+    fn int_function_follows_latency() {
+        let def = service_def(1, 1, OnWarning::Failure);
+        // A local preference: the higher, the more preferred
+        let local_pref = IntFunction {
+            function_name: "service_local_pref".to_owned(),
+            source: IntSource::Latency {
+                best_latency: Duration::from_millis(100),
+                worst_latency: Duration::from_millis(500),
+            },
+            best: 200,
+            worst: 100,
+            step: 1,
+        };
+        let up = ServiceState::Success {
+            nb_of_failure: 0,
+            degraded: false,
+            last_transition: None,
+        };
+        let value = |millis| local_pref.value(&def, &up, Some(Duration::from_millis(millis)), true);
+
+        assert_eq!(value(10), 200);
+        assert_eq!(value(100), 200);
+        assert_eq!(value(200), 175);
+        assert_eq!(value(500), 100);
+        assert_eq!(value(2000), 100);
+        // Before the first check
+        assert_eq!(local_pref.value(&def, &up, None, true), 100);
+    }
+
+    #[test]
+    fn override_takes_precedence_until_expiry() {
+        let definitions = vec![
+            ServiceDefinition {
+                service_name: "frontend".to_owned(),
+                function_name: "frontend_is_up".to_owned(),
+                depends_on: vec!["backend".to_owned()],
+                ..service_def(1, 1, OnWarning::Failure)
+            },
+            ServiceDefinition {
+                service_name: "backend".to_owned(),
+                function_name: "backend_is_up".to_owned(),
+                initial_state: InitialState::Up,
+                ..service_def(1, 1, OnWarning::Failure)
+            },
+        ];
+        let mut bundle = Bundle::new(config(definitions), T0);
+        let until = T0 + Duration::from_mins(10);
+
+        assert!(bundle
+            .set_override(
+                "frontend_is_up",
+                Override::new(OverrideMode::ForcedUp, None, "testing".to_owned())
+            )
+            .is_ok());
+        assert_eq!(
+            bundle.function_values[0],
+            FunctionValue {
+                value: true,
+                reason: "forced up by operator: testing".to_owned()
+            }
+        );
+
+        assert!(bundle
+            .set_override(
+                "backend_is_up",
+                Override::new(
+                    OverrideMode::ForcedDown,
+                    Some(until),
+                    "maintenance".to_owned()
+                )
+            )
+            .is_ok());
+        assert!(!bundle.function_values[1].value);
+        // The check keeps its own state
+        assert!(bundle.service_states[1].is_up());
+        assert_eq!(bundle.next_override_expiry(), Some(until));
+
+        assert!(!bundle.expire_overrides(until - Duration::from_secs(1)));
+        assert!(bundle.expire_overrides(until));
+        assert!(bundle.function_values[1].value);
+        assert_eq!(bundle.next_override_expiry(), None);
+
+        assert!(bundle
+            .set_override(
+                "frontend_is_up",
+                Override::new(OverrideMode::Auto, None, String::new())
+            )
+            .is_ok());
+        assert_eq!(bundle.overrides, [None, None]);
+
+        assert_eq!(
+            bundle.set_override("unknown", None),
+            Err("No service defines the BIRD function 'unknown'".to_owned())
+        );
+    }
+
+    #[test]
+    fn drain_file_withdraws_tagged_services() {
+        let tagged = |function_name: &str, tags: &[&str]| ServiceDefinition {
+            service_name: function_name.to_owned(),
+            function_name: function_name.to_owned(),
+            initial_state: InitialState::Up,
+            tags: tags.iter().map(|&t| t.to_owned()).collect(),
+            ..service_def(1, 1, OnWarning::Failure)
+        };
+        let mut config = config(vec![
+            tagged("anycast_is_up", &["anycast"]),
+            tagged("unicast_is_up", &[]),
+        ]);
+        config.drain_file = Some(DrainFile {
+            path: "/etc/birdwatcher/drain".to_owned(),
+            tags: Some(vec!["anycast".to_owned()]),
+        });
+        let mut bundle = Bundle::new(config, T0);
+        assert!(bundle
+            .set_override(
+                "anycast_is_up",
+                Override::new(OverrideMode::ForcedUp, None, "testing".to_owned())
+            )
+            .is_ok());
+
+        assert!(bundle.set_drained(true));
+        assert!(!bundle.set_drained(true));
+        // The drain file wins over an override
+        assert_eq!(
+            bundle.function_values,
+            [
+                FunctionValue {
+                    value: false,
+                    reason: "drained: /etc/birdwatcher/drain exists".to_owned()
+                },
+                FunctionValue {
+                    value: true,
+                    reason: "check passing".to_owned()
+                },
+            ]
+        );
+
+        assert!(bundle.set_drained(false));
+        assert!(bundle.function_values.iter().all(|v| v.value));
+    }
+}
@@ -0,0 +1,161 @@
+//! Save the state of the services in a file, so that a restart of the daemon (upgrade, config change)
+//! does not withdraw or announce routes spuriously.
+//!
+//! Services are matched by their `function_name`, so services can be added, removed or reordered between two runs.
+
+use std::{
+    io::Write as _,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use color_eyre::{eyre::Context as _, Result};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::service::{service_id_by_function_name, Bundle, DampeningState, ServiceState};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SavedStates {
+    saved_at: SystemTime,
+    services: Vec<SavedService>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedService {
+    function_name: String,
+    state: ServiceState,
+    dampening: DampeningState,
+}
+
+impl SavedStates {
+    #[must_use]
+    pub fn from_bundle(bundle: &Bundle, now: SystemTime) -> SavedStates {
+        SavedStates {
+            saved_at: now,
+            services: bundle
+                .config
+                .service_definitions
+                .iter()
+                .zip(&bundle.service_states)
+                .zip(&bundle.dampening_states)
+                .map(|((def, state), dampening)| SavedService {
+                    function_name: def.function_name.clone(),
+                    state: state.clone(),
+                    dampening: dampening.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Write the file atomically: a crash while writing leaves the previous version untouched
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let content = toml::to_string(self)?;
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let mut file = fs_err::File::create(&tmp_path)?;
+        file.write_all(content.as_bytes())?;
+        // Without it, a power loss right after the rename may leave an empty file
+        file.sync_all()?;
+        fs_err::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Restore the state of the services of `bundle` saved in `path`.
+    /// Nothing is restored if the file does not exist, or if it has been saved more than `max_age` ago
+    pub fn restore(
+        path: &Path,
+        max_age: Duration,
+        bundle: &mut Bundle,
+        now: SystemTime,
+    ) -> Result<()> {
+        let content = match fs_err::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                info!("No state file {}, starting from scratch", path.display());
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let saved: SavedStates = toml::from_str(&content)
+            .wrap_err_with(|| format!("Invalid state file {}", path.display()))?;
+
+        let age = now.duration_since(saved.saved_at).unwrap_or_default();
+        if age > max_age {
+            info!(
+                "Ignoring state file {}: saved {}s ago, more than max_age {}s",
+                path.display(),
+                age.as_secs(),
+                max_age.as_secs()
+            );
+            return Ok(());
+        }
+
+        for saved_service in saved.services {
+            if let Some(id) = service_id_by_function_name(
+                &bundle.config.service_definitions,
+                &saved_service.function_name,
+            ) {
+                info!(
+                    "Restored the state of {}: {:?}",
+                    saved_service.function_name, saved_service.state
+                );
+                bundle.service_states[id] = saved_service.state;
+                bundle.dampening_states[id] = saved_service.dampening;
+            }
+        }
+        bundle.update_function_values();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use super::SavedStates;
+    use crate::{
+        config::Config,
+        service::{Bundle, ServiceState},
+    };
+
+    #[test]
+    fn save_and_restore() {
+        let config = Config::load_from_file("example/birdwatcher.conf".as_ref()).unwrap();
+        let now = SystemTime::now();
+        let path =
+            std::env::temp_dir().join(format!("birdwatcher_state_{}.toml", std::process::id()));
+
+        let mut bundle = Bundle::new(config.clone(), now);
+        bundle.service_states[1] = ServiceState::Success {
+            nb_of_failure: 1,
+            degraded: false,
+            last_transition: Some(now),
+        };
+        SavedStates::from_bundle(&bundle, now).save(&path).unwrap();
+
+        let mut restored = Bundle::new(config.clone(), now);
+        SavedStates::restore(
+            &path,
+            Duration::from_mins(5),
+            &mut restored,
+            now + Duration::from_secs(10),
+        )
+        .unwrap();
+        assert!(restored.service_states[1].is_up());
+        assert!(restored.function_values[1].value);
+
+        // Too old
+        let mut restored = Bundle::new(config, now);
+        SavedStates::restore(
+            &path,
+            Duration::from_mins(5),
+            &mut restored,
+            now + Duration::from_mins(6),
+        )
+        .unwrap();
+        assert!(!restored.service_states[1].is_up());
+
+        fs_err::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,62 @@
+//! A minimal template syntax for the generated file: each `{{ name }}` is replaced by the value of the variable `name`.
+
+use color_eyre::{eyre::eyre, Result};
+
+/// Replace the variables of `template` by their `value`
+///
+/// # Errors
+///
+/// Will return `Err` if `value` does not know a variable, or if a `{{` is not closed
+pub fn render(template: &str, value: impl Fn(&str) -> Option<String>) -> Result<String> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after
+            .find("}}")
+            .ok_or_else(|| eyre!("'{{{{' is not closed by '}}}}'"))?;
+        let name = after[..end].trim();
+        rendered.push_str(&value(name).ok_or_else(|| eyre!("Unknown variable '{name}'"))?);
+        rest = &after[end + 2..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+#[cfg(test)]
+mod test {
+    use super::render;
+
+    fn value(name: &str) -> Option<String> {
+        match name {
+            "function_name" => Some("is_up".to_owned()),
+            "value" => Some("true".to_owned()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn variables() {
+        assert_eq!(
+            render("define {{function_name}}_value = {{ value }};", value).unwrap(),
+            "define is_up_value = true;"
+        );
+        assert_eq!(
+            render("function f() { return true; }", value).unwrap(),
+            "function f() { return true; }"
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(
+            render("{{ community }}", value).unwrap_err().to_string(),
+            "Unknown variable 'community'"
+        );
+        assert_eq!(
+            render("{{ value", value).unwrap_err().to_string(),
+            "'{{' is not closed by '}}'"
+        );
+    }
+}
@@ -2,7 +2,6 @@
 //! It is used by `birdwatcher-cli` to display the state of the services.
 
 use std::{
-    iter::zip,
     sync::{Arc, Mutex},
     time::Duration,
 };
@@ -22,7 +21,7 @@ use tokio::select;
 
 use futures_timer::Delay;
 
-use crate::service::Bundle;
+use crate::service::{Bundle, OutputStatus};
 
 struct TableColors {
     buffer_bg: Color,
@@ -153,7 +152,7 @@ impl App {
                 let rects = vertical.split(frame.area());
 
                 self.render_table(frame, rects[0], bundle);
-                self.render_footer(frame, rects[1]);
+                self.render_footer(frame, rects[1], bundle);
             }
         }
     }
@@ -170,38 +169,73 @@ impl App {
             .add_modifier(Modifier::REVERSED)
             .fg(self.colors.selected_cell_style_fg);
 
-        let header = ["Fn name", "Interval", "State"]
-            .into_iter()
-            .map(Cell::from)
-            .collect::<Row>()
-            .style(header_style)
-            .height(1);
-
-        let services = zip(
-            bundle.config.service_definitions.iter(),
-            bundle.service_states.iter(),
-        );
-
-        let rows = services
-            .enumerate()
-            .map(|(i, (service_definition, service_state))| {
-                let color = match i % 2 {
-                    0 => self.colors.normal_row_color,
-                    _ => self.colors.alt_row_color,
-                };
-                let item = [
-                    &service_definition.function_name.clone(),
-                    &format!("{}s", service_definition.interval.as_secs()),
-                    &format!("{service_state:?}"),
-                ];
-                item.into_iter()
-                    .map(|content| Cell::from(Text::from(format!("\n{content}\n"))))
-                    .collect::<Row>()
-                    .style(Style::new().fg(self.colors.row_fg).bg(color))
-                    .height(4)
-            });
+        let header = [
+            "Fn name",
+            "Interval",
+            "State",
+            "Penalty",
+            "Override",
+            "Function",
+            "Last check",
+        ]
+        .into_iter()
+        .map(Cell::from)
+        .collect::<Row>()
+        .style(header_style)
+        .height(1);
+
+        let rows =
+            bundle
+                .config
+                .service_definitions
+                .iter()
+                .enumerate()
+                .map(|(i, service_definition)| {
+                    let color = match i % 2 {
+                        0 => self.colors.normal_row_color,
+                        _ => self.colors.alt_row_color,
+                    };
+                    let dampening_state = &bundle.dampening_states[i];
+                    let function_value = &bundle.function_values[i];
+                    let item = [
+                        &service_definition.function_name.clone(),
+                        &format!("{}s", service_definition.interval.as_secs()),
+                        &format!("{:?}", bundle.service_states[i]),
+                        &match (&service_definition.dampening, dampening_state.suppressed) {
+                            (None, _) => "-".to_owned(),
+                            (Some(_), false) => format!("{:.0}", dampening_state.penalty),
+                            (Some(_), true) => {
+                                format!("{:.0} (suppressed)", dampening_state.penalty)
+                            }
+                        },
+                        &bundle.overrides[i].as_ref().map_or_else(
+                            || "-".to_owned(),
+                            |r#override| {
+                                let forced = if r#override.value { "up" } else { "down" };
+                                let left = r#override.until.map_or_else(String::new, |until| {
+                                    let left = until
+                                        .duration_since(std::time::SystemTime::now())
+                                        .unwrap_or_default();
+                                    format!(", {}s left", left.as_secs())
+                                });
+                                format!("{forced} ({}){left}", r#override.reason)
+                            },
+                        ),
+                        &format!("{} ({})", function_value.value, function_value.reason),
+                        &bundle.last_outcomes[i]
+                            .as_ref()
+                            .map_or_else(String::new, |outcome| {
+                                format!("{:?}: {}", outcome.status, outcome.reason)
+                            }),
+                    ];
+                    item.into_iter()
+                        .map(|content| Cell::from(Text::from(format!("\n{content}\n"))))
+                        .collect::<Row>()
+                        .style(Style::new().fg(self.colors.row_fg).bg(color))
+                        .height(4)
+                });
         let bar = " █ ";
-        let table = Table::new(rows, Constraint::from_fills([1, 1, 3]))
+        let table = Table::new(rows, Constraint::from_fills([1, 1, 3, 1, 2, 2, 3]))
             .header(header)
             .row_highlight_style(selected_row_style)
             .column_highlight_style(selected_col_style)
@@ -217,10 +251,32 @@ impl App {
         frame.render_stateful_widget(table, area, &mut self.state);
     }
 
-    fn render_footer(&self, frame: &mut Frame, area: Rect) {
-        const INFO_TEXT: [&str; 1] = ["(Esc) quit | (↑) move up | (↓) move down"];
+    fn render_footer(&self, frame: &mut Frame, area: Rect, bundle: &Bundle) {
+        const INFO_TEXT: &str = "(Esc) quit | (↑) move up | (↓) move down";
 
-        let info_footer = Paragraph::new(Text::from_iter(INFO_TEXT))
+        let drain_text = match &bundle.config.drain_file {
+            Some(drain_file) if bundle.drained => {
+                Some(format!("Drained: {} exists", drain_file.path))
+            }
+            _ => None,
+        };
+        let several_outputs = bundle.config.outputs.len() > 1;
+        let status_text = drain_text
+            .into_iter()
+            .chain(bundle.config.outputs.iter().zip(&bundle.outputs).flat_map(
+                |(output, status)| {
+                    output_status_texts(status).into_iter().map(move |text| {
+                        if several_outputs {
+                            format!("{}: {text}", output.generated_file.path)
+                        } else {
+                            text
+                        }
+                    })
+                },
+            ))
+            .collect::<Vec<_>>()
+            .join(" | ");
+        let info_footer = Paragraph::new(Text::from_iter([INFO_TEXT.to_owned(), status_text]))
             .style(
                 Style::new()
                     .fg(self.colors.row_fg)
@@ -235,3 +291,44 @@ impl App {
         frame.render_widget(info_footer, area);
     }
 }
+
+/// The problems of the last update of an output, if any
+fn output_status_texts(status: &OutputStatus) -> Vec<String> {
+    let validation_text = status
+        .validation_error
+        .as_ref()
+        .map(|e| format!("Generated file refused: {e}"));
+    let reload_text = status.last_reload.as_ref().and_then(|reload| {
+        let error = reload.error.as_ref()?;
+        let ago = std::time::SystemTime::now()
+            .duration_since(reload.at)
+            .unwrap_or_default();
+        Some(format!(
+            "BIRD out of sync, reload failed {}s ago: {error}",
+            ago.as_secs()
+        ))
+    });
+    let verification_text = status.verification.as_ref().and_then(|verification| {
+        if let Some(e) = &verification.error {
+            return Some(format!("Cannot verify the routes exported by BIRD: {e}"));
+        }
+        let mismatches = verification
+            .mismatches
+            .iter()
+            .map(|mismatch| {
+                let state = if mismatch.expected {
+                    "missing"
+                } else {
+                    "exported"
+                };
+                format!("{} {state}", mismatch.prefix)
+            })
+            .collect::<Vec<_>>();
+        (!mismatches.is_empty()).then(|| format!("BIRD export mismatch: {}", mismatches.join(", ")))
+    });
+    validation_text
+        .into_iter()
+        .chain(reload_text)
+        .chain(verification_text)
+        .collect()
+}
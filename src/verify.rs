@@ -0,0 +1,224 @@
+//! Check, after a reload, that BIRD exports the `prefixes` of the services whose function returns true, and only those.
+
+use std::{collections::HashSet, net::IpAddr, time::SystemTime};
+
+use color_eyre::{
+    eyre::{bail, Context as _},
+    Result,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{process::Command, time::timeout};
+
+use crate::{
+    bird_control,
+    config::{BirdQuery, Output, Verify},
+    service::Bundle,
+};
+
+/// A prefix whose export does not match the function value of its service
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ExportMismatch {
+    pub function_name: String,
+    pub prefix: String,
+    /// The value returned by the BIRD function, so whether the prefix should be exported
+    pub expected: bool,
+}
+
+/// Result of the verification following a reload
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Verification {
+    pub at: SystemTime,
+    /// Set if BIRD could not be queried. `mismatches` is then empty
+    pub error: Option<String>,
+    pub mismatches: Vec<ExportMismatch>,
+}
+
+impl Verification {
+    /// Compare what BIRD exports with the function values of the services of `output`
+    #[must_use]
+    pub fn new(
+        expected: &Bundle,
+        output: &Output,
+        exported: Result<HashSet<(IpAddr, u8)>>,
+        at: SystemTime,
+    ) -> Self {
+        match exported {
+            Ok(exported) => Verification {
+                at,
+                error: None,
+                mismatches: mismatches(expected, output, &exported),
+            },
+            Err(e) => Verification {
+                at,
+                error: Some(format!("{e:#}")),
+                mismatches: Vec::new(),
+            },
+        }
+    }
+}
+
+/// Parse a prefix like `192.0.2.1/32` or `2001:db8::/32`
+#[must_use]
+pub fn parse_prefix(prefix: &str) -> Option<(IpAddr, u8)> {
+    let (address, length) = prefix.split_once('/')?;
+    let address: IpAddr = address.parse().ok()?;
+    let length: u8 = length.parse().ok()?;
+    let max_length = if address.is_ipv4() { 32 } else { 128 };
+    (length <= max_length).then_some((address, length))
+}
+
+/// Query BIRD for the routes exported by `verify.protocol`
+///
+/// # Errors
+///
+/// Will return `Err` if BIRD cannot be queried before `timeout_duration`
+pub async fn exported_prefixes(
+    verify: &Verify,
+    timeout_duration: std::time::Duration,
+) -> Result<HashSet<(IpAddr, u8)>> {
+    let command = format!("show route export {}", verify.protocol);
+    let lines = match &verify.query {
+        BirdQuery::Socket(socket) => {
+            timeout(timeout_duration, bird_control::request(socket, &command))
+                .await
+                .wrap_err(format!("No reply from the BIRD socket {socket}"))??
+        }
+        BirdQuery::Command {
+            command: executable,
+            args,
+        } => {
+            let output = timeout(
+                timeout_duration,
+                Command::new(executable)
+                    .args(args)
+                    .args(command.split(' '))
+                    .output(),
+            )
+            .await
+            .wrap_err(format!("{executable} timed out"))?
+            .wrap_err(format!("Could not launch {executable}"))?;
+            if !output.status.success() {
+                bail!(
+                    "{executable} failed. stderr = {}",
+                    String::from_utf8_lossy(&output.stderr)
+                );
+            }
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .map(str::to_owned)
+                .collect()
+        }
+    };
+    Ok(parse_routes(lines.iter().map(String::as_str)))
+}
+
+/// The prefixes of the routes listed by `show route`. The other lines, e.g. the next hops, are ignored
+fn parse_routes<'a>(lines: impl IntoIterator<Item = &'a str>) -> HashSet<(IpAddr, u8)> {
+    lines
+        .into_iter()
+        .filter_map(|line| line.split_whitespace().next().and_then(parse_prefix))
+        .collect()
+}
+
+fn mismatches(
+    expected: &Bundle,
+    output: &Output,
+    exported: &HashSet<(IpAddr, u8)>,
+) -> Vec<ExportMismatch> {
+    expected
+        .config
+        .service_definitions
+        .iter()
+        .zip(&expected.function_values)
+        .filter(|(def, _)| output.includes(def))
+        .flat_map(|(def, function_value)| {
+            def.prefixes.iter().filter_map(move |prefix| {
+                let is_exported = parse_prefix(prefix).is_some_and(|p| exported.contains(&p));
+                (is_exported != function_value.value).then(|| ExportMismatch {
+                    function_name: def.function_name.clone(),
+                    prefix: prefix.clone(),
+                    expected: function_value.value,
+                })
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::IpAddr, time::SystemTime};
+
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+
+    use super::{mismatches, parse_prefix, parse_routes, ExportMismatch};
+    use crate::{
+        config::{Config, Output},
+        service::Bundle,
+    };
+
+    #[test]
+    fn prefix() {
+        assert_eq!(
+            parse_prefix("192.0.2.1/32"),
+            Some(("192.0.2.1".parse::<IpAddr>().unwrap(), 32))
+        );
+        assert_eq!(
+            parse_prefix("2001:db8::/32"),
+            Some(("2001:db8::".parse::<IpAddr>().unwrap(), 32))
+        );
+        assert_eq!(parse_prefix("192.0.2.1/33"), None);
+        assert_eq!(parse_prefix("192.0.2.1"), None);
+    }
+
+    #[test]
+    fn routes_from_birdc() {
+        let output = indoc! {"
+            BIRD 2.15 ready.
+            Table master4:
+            192.0.2.1/32         unicast [static1 12:00:00.000] * (200)
+            	via 10.0.0.1 on eth0
+            198.51.100.0/24      unicast [static1 12:00:00.000] * (200)
+            	via 10.0.0.1 on eth0
+        "};
+        let routes = parse_routes(output.lines());
+        assert_eq!(routes.len(), 2);
+        assert!(routes.contains(&parse_prefix("198.51.100.0/24").unwrap()));
+    }
+
+    #[test]
+    fn mismatch() {
+        let config = Config::load_from_file("example/birdwatcher.conf".as_ref()).unwrap();
+        let mut bundle = Bundle::new(config, SystemTime::UNIX_EPOCH);
+        bundle.config.service_definitions[0].prefixes = vec!["192.0.2.1/32".to_owned()];
+        bundle.config.service_definitions[1].prefixes = vec!["192.0.2.2/32".to_owned()];
+        bundle.function_values[0].value = true;
+        bundle.function_values[1].value = false;
+
+        // Both prefixes are exported, but the second service is down
+        let exported = parse_routes(["192.0.2.1/32 unicast", "192.0.2.2/32 unicast"]);
+        let output = bundle.config.outputs[0].clone();
+        assert_eq!(
+            mismatches(&bundle, &output, &exported),
+            [ExportMismatch {
+                function_name: "match_false".to_owned(),
+                prefix: "192.0.2.2/32".to_owned(),
+                expected: false,
+            }]
+        );
+
+        assert_eq!(
+            mismatches(&bundle, &output, &parse_routes(["192.0.2.1/32"])),
+            []
+        );
+
+        // The second service is not part of the output
+        let output = Output {
+            services: Some(vec![bundle.config.service_definitions[0]
+                .service_name
+                .clone()]),
+            ..output
+        };
+        assert_eq!(mismatches(&bundle, &output, &exported), []);
+    }
+}